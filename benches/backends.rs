@@ -0,0 +1,77 @@
+//! Instructions per second for each execution backend.
+//!
+//! Run with `cargo bench`. By default this runs a built-in loop of arithmetic,
+//! memory and drawing instructions; pass a ROM to measure that instead:
+//! `cargo bench -- games/PONG`.
+
+extern crate chip8;
+
+use chip8::backend::{self, Backend};
+use chip8::{CPU, PROGRAM_ROM_START};
+
+use std::env;
+use std::time::Instant;
+
+/// Instructions executed per backend.
+const CYCLES: usize = 20_000_000;
+/// Instructions per call to `Backend::run`.
+const CHUNK: usize = 10_000;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const WORKLOAD: [u8; 20] = [
+    0xA3, 0x00, // 200: LD I, 0x300
+    0x70, 0x01, // 202: ADD V0, 0x01
+    0x81, 0x04, // 204: ADD V1, V0
+    0x82, 0x15, // 206: SUB V2, V1
+    0x83, 0x23, // 208: XOR V3, V2
+    0xF3, 0x55, // 20A: LD [I], V3
+    0xF3, 0x65, // 20C: LD V3, [I]
+    0x84, 0x06, // 20E: SHR V4
+    0xD4, 0x51, // 210: DRW V4, V5, 1
+    0x12, 0x02, // 212: JP 0x202
+];
+
+fn bench(name: &str, backend: &mut dyn Backend, rom: Option<&str>) {
+    let mut cpu = CPU::new();
+    match rom {
+        Some(path) => cpu.load_rom(path),
+        None => {
+            let end = PROGRAM_ROM_START + WORKLOAD.len();
+            cpu.memory[PROGRAM_ROM_START..end].copy_from_slice(&WORKLOAD);
+        }
+    }
+
+    let start = Instant::now();
+    let mut executed = 0;
+    while executed < CYCLES {
+        executed += backend.run(&mut cpu, CHUNK);
+
+        if let Some(fault) = cpu.fault {
+            println!("{}: stopped by {}", name, fault);
+            break;
+        }
+        // Nobody's at the keyboard, so answer key waits with key 0
+        if cpu.waitkey {
+            cpu.finish_waitkey(0);
+        }
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    println!(
+        "{:>12}: {:>6.1} million instructions/s ({} in {:.2}s)",
+        name,
+        executed as f64 / seconds / 1e6,
+        executed,
+        seconds
+    );
+}
+
+fn main() {
+    // Cargo passes --bench, anything else is the ROM
+    let rom = env::args().skip(1).find(|arg| !arg.starts_with("--"));
+
+    for name in backend::NAMES {
+        let mut backend = backend::by_name(name).unwrap();
+        bench(name, &mut *backend, rom.as_deref());
+    }
+}
@@ -0,0 +1,494 @@
+//! Static control-flow analysis of CHIP-8 ROMs.
+//!
+//! Starting at the entry point we follow every path the program can take
+//! (jumps, calls, returns, skips and `Bnnn` with V0 = 0) to find which bytes
+//! are instructions. Along the way we keep track of the value of I so that
+//! memory drawn by `Dxyn` can be marked as sprite data. The result can be
+//! exported as a Graphviz DOT graph.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use disasm::disassemble;
+use Opcode;
+
+/// How control gets from one basic block to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Execution runs off the end of the block into the next one.
+    Fallthrough,
+    /// `1nnn`.
+    Jump,
+    /// `2nnn`, to the start of a subroutine.
+    Call,
+    /// A skip instruction that was taken.
+    Skip,
+    /// `Bnnn`, where the real target depends on V0.
+    Indirect,
+}
+
+/// A run of instructions that is only ever entered at the top.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// Address of the last instruction in the block.
+    pub end: usize,
+    /// Blocks control can continue to, and how.
+    pub successors: Vec<(usize, EdgeKind)>,
+}
+
+/// The control-flow graph of a ROM, along with what we could work out about
+/// which bytes are code and which are data.
+pub struct ControlFlowGraph {
+    /// The ROM, as loaded at `base`.
+    rom: Vec<u8>,
+    base: usize,
+    pub entry: usize,
+    /// Basic blocks, keyed by their start address.
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    /// Start addresses of every called subroutine.
+    pub subroutines: BTreeSet<usize>,
+    /// Sprites drawn with a known I, keyed by address, with the tallest
+    /// height they were drawn at.
+    pub sprites: BTreeMap<usize, usize>,
+    /// (instruction address, sprite address) for every `Dxyn` with known I.
+    pub sprite_refs: BTreeSet<(usize, usize)>,
+    /// Addresses of every reachable instruction.
+    instructions: BTreeSet<usize>,
+}
+
+/// What executing the instruction at an address can lead to.
+struct Flow {
+    /// Where execution can go next.
+    next: Vec<(usize, EdgeKind)>,
+    /// Whether the instruction always ends a basic block.
+    ends_block: bool,
+}
+
+impl ControlFlowGraph {
+    /// Analyze a ROM that gets loaded at `base` and starts running at
+    /// `entry`, e.g. `Platform::program_start` and `Platform::entry_point`.
+    pub fn analyze(rom: &[u8], base: usize, entry: usize) -> ControlFlowGraph {
+        let mut graph = ControlFlowGraph {
+            rom: rom.to_vec(),
+            base,
+            entry,
+            blocks: BTreeMap::new(),
+            subroutines: BTreeSet::new(),
+            sprites: BTreeMap::new(),
+            sprite_refs: BTreeSet::new(),
+            instructions: BTreeSet::new(),
+        };
+
+        graph.trace();
+        graph.build_blocks();
+        graph
+    }
+
+    /// The opcode at `addr`, if it lies entirely within the ROM.
+    fn opcode_at(&self, addr: usize) -> Option<u16> {
+        if addr < self.base || addr + 1 >= self.base + self.rom.len() {
+            return None;
+        }
+        let offset = addr - self.base;
+        Some(((self.rom[offset] as u16) << 8) | self.rom[offset + 1] as u16)
+    }
+
+    /// Where control can go after the instruction at `addr`.
+    fn flow(&self, addr: usize, opcode: u16) -> Flow {
+        let next = addr + 2;
+
+        match opcode & 0xF000 {
+            0x0000 if opcode == 0x00EE => Flow {
+                next: vec![],
+                ends_block: true,
+            },
+            0x1000 => Flow {
+                next: vec![(opcode.nnn(), EdgeKind::Jump)],
+                ends_block: true,
+            },
+            0x2000 => Flow {
+                next: vec![
+                    (opcode.nnn(), EdgeKind::Call),
+                    (next, EdgeKind::Fallthrough),
+                ],
+                ends_block: true,
+            },
+            0x3000 | 0x4000 | 0x5000 | 0x9000 => Flow {
+                next: vec![(next, EdgeKind::Fallthrough), (next + 2, EdgeKind::Skip)],
+                ends_block: true,
+            },
+            0xB000 => Flow {
+                next: vec![(opcode.nnn(), EdgeKind::Indirect)],
+                ends_block: true,
+            },
+            0xE000 if opcode.kk() == 0x9E || opcode.kk() == 0xA1 => Flow {
+                next: vec![(next, EdgeKind::Fallthrough), (next + 2, EdgeKind::Skip)],
+                ends_block: true,
+            },
+            _ => Flow {
+                next: vec![(next, EdgeKind::Fallthrough)],
+                ends_block: false,
+            },
+        }
+    }
+
+    /// Walk every reachable path, recording instructions, subroutines and
+    /// sprites. I is tracked along each path so the same code reached with a
+    /// different I is walked again.
+    fn trace(&mut self) {
+        let mut visited: BTreeSet<(usize, Option<usize>)> = BTreeSet::new();
+        let mut worklist = vec![(self.entry, None)];
+
+        while let Some((addr, i_addr)) = worklist.pop() {
+            if !visited.insert((addr, i_addr)) {
+                continue;
+            }
+            let opcode = match self.opcode_at(addr) {
+                Some(opcode) => opcode,
+                None => continue,
+            };
+            self.instructions.insert(addr);
+
+            // Work out the value of I after this instruction, if we know it.
+            let i_addr = match opcode & 0xF0FF {
+                _ if opcode & 0xF000 == 0xA000 => Some(opcode.nnn()),
+                0xF01E | 0xF029 => None,
+                _ => i_addr,
+            };
+
+            if opcode & 0xF000 == 0xD000 {
+                if let Some(sprite) = i_addr {
+                    let height = self.sprites.entry(sprite).or_insert(0);
+                    *height = (*height).max(opcode.n());
+                    self.sprite_refs.insert((addr, sprite));
+                }
+            }
+
+            for (target, kind) in self.flow(addr, opcode).next {
+                let i_addr = match kind {
+                    EdgeKind::Call => {
+                        self.subroutines.insert(target);
+                        i_addr
+                    }
+                    // The subroutine may have set I by the time it returns
+                    EdgeKind::Fallthrough if opcode & 0xF000 == 0x2000 => None,
+                    _ => i_addr,
+                };
+                worklist.push((target, i_addr));
+            }
+        }
+    }
+
+    /// Split the reachable instructions up into basic blocks.
+    fn build_blocks(&mut self) {
+        // Every branch target, and anything following a branch, starts a block.
+        let mut leaders: BTreeSet<usize> = BTreeSet::new();
+        leaders.insert(self.entry);
+        for &addr in &self.instructions {
+            let opcode = self.opcode_at(addr).unwrap();
+            let flow = self.flow(addr, opcode);
+            if flow.ends_block {
+                leaders.extend(flow.next.iter().map(|&(target, _)| target));
+            }
+        }
+        leaders.retain(|addr| self.instructions.contains(addr));
+
+        for &start in &leaders {
+            let mut addr = start;
+            loop {
+                let opcode = self.opcode_at(addr).unwrap();
+                let flow = self.flow(addr, opcode);
+                let next = addr + 2;
+
+                if flow.ends_block || !self.instructions.contains(&next) || leaders.contains(&next)
+                {
+                    let successors = flow
+                        .next
+                        .into_iter()
+                        .filter(|&(target, _)| self.instructions.contains(&target))
+                        .collect();
+                    self.blocks.insert(
+                        start,
+                        BasicBlock {
+                            start,
+                            end: addr,
+                            successors,
+                        },
+                    );
+                    break;
+                }
+                addr = next;
+            }
+        }
+    }
+
+    /// Whether the instruction at `addr` is reachable.
+    pub fn is_instruction(&self, addr: usize) -> bool {
+        self.instructions.contains(&addr)
+    }
+
+    /// Whether `addr` is part of a sprite drawn by `Dxyn`.
+    pub fn is_sprite(&self, addr: usize) -> bool {
+        // Sprites are at most 15 bytes tall
+        self.sprites
+            .range(addr.saturating_sub(15)..=addr)
+            .any(|(&start, &height)| addr < start + height)
+    }
+
+    /// The blocks belonging to the subroutine starting at `entry`: everything
+    /// reachable from it without following calls.
+    pub fn subroutine_blocks(&self, entry: usize) -> BTreeSet<usize> {
+        let mut seen = BTreeSet::new();
+        let mut worklist = vec![entry];
+
+        while let Some(start) = worklist.pop() {
+            if !seen.insert(start) {
+                continue;
+            }
+            if let Some(block) = self.blocks.get(&start) {
+                for &(target, kind) in &block.successors {
+                    if kind != EdgeKind::Call {
+                        worklist.push(target);
+                    }
+                }
+            }
+        }
+        seen
+    }
+
+    /// Ranges of ROM bytes, as `[start, end)` addresses, that are neither
+    /// reachable code nor known sprite data.
+    pub fn unreachable_regions(&self) -> Vec<(usize, usize)> {
+        let end = self.base + self.rom.len();
+        let mut regions = Vec::new();
+        let mut region_start = None;
+
+        for addr in self.base..end {
+            let used = self.is_sprite(addr)
+                || self.is_instruction(addr)
+                || (addr > 0 && self.is_instruction(addr - 1));
+
+            match (used, region_start) {
+                (false, None) => region_start = Some(addr),
+                (true, Some(start)) => {
+                    regions.push((start, addr));
+                    region_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = region_start {
+            regions.push((start, end));
+        }
+        regions
+    }
+
+    /// Export the graph in Graphviz DOT format. Blocks are labelled with their
+    /// disassembly, subroutine entries are highlighted and sprite data is
+    /// linked to the blocks that draw it with dashed edges.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph rom {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if block.start == self.entry {
+                label.push_str("entry\\l");
+            } else if self.subroutines.contains(&block.start) {
+                label.push_str(&format!("sub_{:03X}\\l", block.start));
+            }
+            let mut addr = block.start;
+            while addr <= block.end {
+                let opcode = self.opcode_at(addr).unwrap();
+                label.push_str(&format!("{:03X}: {}\\l", addr, disassemble(opcode)));
+                addr += 2;
+            }
+
+            let style = if self.subroutines.contains(&block.start) {
+                ", style=filled, fillcolor=lightblue"
+            } else {
+                ""
+            };
+            dot.push_str(&format!(
+                "    b{:03X} [label=\"{}\"{}];\n",
+                block.start, label, style
+            ));
+
+            for &(target, kind) in &block.successors {
+                let attrs = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jp\"]",
+                    EdgeKind::Call => " [label=\"call\", color=blue]",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Indirect => " [label=\"jp v0\", style=dotted]",
+                };
+                dot.push_str(&format!(
+                    "    b{:03X} -> b{:03X}{};\n",
+                    block.start, target, attrs
+                ));
+            }
+        }
+
+        for (&sprite, &height) in &self.sprites {
+            dot.push_str(&format!(
+                "    s{:03X} [label=\"sprite {:03X}-{:03X}\", shape=note, color=gray];\n",
+                sprite,
+                sprite,
+                sprite + height.max(1) - 1
+            ));
+        }
+        for &(instruction, sprite) in &self.sprite_refs {
+            if let Some(block) = self.block_containing(instruction) {
+                dot.push_str(&format!(
+                    "    b{:03X} -> s{:03X} [style=dashed, color=gray];\n",
+                    block, sprite
+                ));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Start address of the block that contains the instruction at `addr`.
+    fn block_containing(&self, addr: usize) -> Option<usize> {
+        self.blocks
+            .range(..=addr)
+            .rev()
+            .find(|(_, block)| addr <= block.end)
+            .map(|(&start, _)| start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PROGRAM_ROM_START;
+
+    fn analyze(rom: &[u8]) -> ControlFlowGraph {
+        ControlFlowGraph::analyze(rom, PROGRAM_ROM_START, PROGRAM_ROM_START)
+    }
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const ROM: [u8; 22] = [
+        0xA2, 0x12, // 200: LD I, 0x212
+        0x22, 0x0A, // 202: CALL 0x20A
+        0x30, 0x01, // 204: SE V0, 0x01
+        0x12, 0x04, // 206: JP 0x204
+        0x12, 0x08, // 208: JP 0x208
+        0xD0, 0x12, // 20A: DRW V0, V1, 2
+        0x00, 0xEE, // 20C: RET
+        0x00, 0xE0, // 20E: CLS (never reached)
+        0x00, 0xE0, // 210: CLS (never reached)
+        0xC0, 0xC0, // 212: sprite
+        0xFF, 0xFF, // 214: never touched
+    ];
+
+    #[test]
+    fn blocks_and_edges() {
+        let graph = analyze(&ROM);
+
+        let starts: Vec<usize> = graph.blocks.keys().cloned().collect();
+        assert_eq!(starts, vec![0x200, 0x204, 0x206, 0x208, 0x20A]);
+
+        assert_eq!(
+            graph.blocks[&0x200].successors,
+            vec![(0x20A, EdgeKind::Call), (0x204, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(
+            graph.blocks[&0x204].successors,
+            vec![(0x206, EdgeKind::Fallthrough), (0x208, EdgeKind::Skip)]
+        );
+        assert_eq!(
+            graph.blocks[&0x206].successors,
+            vec![(0x204, EdgeKind::Jump)]
+        );
+        assert_eq!(graph.blocks[&0x20A].end, 0x20C);
+        assert!(graph.blocks[&0x20A].successors.is_empty());
+    }
+
+    #[test]
+    fn subroutines() {
+        let graph = analyze(&ROM);
+
+        assert_eq!(
+            graph.subroutines.iter().cloned().collect::<Vec<_>>(),
+            vec![0x20A]
+        );
+        assert_eq!(
+            graph
+                .subroutine_blocks(0x20A)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![0x20A]
+        );
+    }
+
+    #[test]
+    fn sprites() {
+        let graph = analyze(&ROM);
+
+        assert_eq!(graph.sprites.get(&0x212), Some(&2));
+        assert!(graph.is_sprite(0x213));
+        assert!(!graph.is_sprite(0x214));
+        assert!(graph.sprite_refs.contains(&(0x20A, 0x212)));
+    }
+
+    #[test]
+    fn subroutine_sets_i() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0xA2, 0x0C, // 200: LD I, 0x20C
+            0x22, 0x08, // 202: CALL 0x208
+            0xD0, 0x12, // 204: DRW V0, V1, 2
+            0x12, 0x06, // 206: JP 0x206
+            0xA2, 0x0E, // 208: LD I, 0x20E
+            0x00, 0xEE, // 20A: RET
+            0xF0, 0xF0, // 20C: not a sprite
+            0x0F, 0x0F, // 20E: sprite
+        ];
+        let graph = analyze(&rom);
+
+        // I isn't known after the call, so the draw isn't pinned on 0x20C
+        assert!(graph.sprites.is_empty());
+        assert!(graph.sprite_refs.is_empty());
+    }
+
+    #[test]
+    fn load_address() {
+        // `ROM` moved up to 0x300, like CHIP-8X programs
+        let mut rom = ROM;
+        for &i in &[0, 2, 6, 8] {
+            rom[i] += 1;
+        }
+        let graph = ControlFlowGraph::analyze(&rom, 0x300, 0x300);
+
+        let starts: Vec<usize> = graph.blocks.keys().cloned().collect();
+        assert_eq!(starts, vec![0x300, 0x304, 0x306, 0x308, 0x30A]);
+        assert!(graph.sprite_refs.contains(&(0x30A, 0x312)));
+        assert_eq!(
+            graph.unreachable_regions(),
+            vec![(0x30E, 0x312), (0x314, 0x316)]
+        );
+    }
+
+    #[test]
+    fn unreachable_regions() {
+        let graph = analyze(&ROM);
+
+        assert_eq!(
+            graph.unreachable_regions(),
+            vec![(0x20E, 0x212), (0x214, 0x216)]
+        );
+    }
+
+    #[test]
+    fn to_dot() {
+        let dot = analyze(&ROM).to_dot();
+
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("b200 -> b20A [label=\"call\", color=blue];"));
+        assert!(dot.contains("b206 -> b204 [label=\"jp\"];"));
+        assert!(dot.contains("b20A -> s212 [style=dashed, color=gray];"));
+    }
+}
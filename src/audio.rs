@@ -0,0 +1,456 @@
+//! The beeper, and writing its output to WAV files.
+//!
+//! CHIP-8 has one sound: a tone that plays while the sound timer is
+//! non-zero. The same `Beeper` feeds the SDL audio queue and recordings, so
+//! a recording sounds like the game did.
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use record::FRAME_RATE;
+use timing::Timing;
+
+/// Sample rate used for playback and recordings.
+pub const SAMPLE_RATE: usize = 44_100;
+/// Samples in one emulated 60 Hz frame.
+pub const SAMPLES_PER_FRAME: usize = SAMPLE_RATE / FRAME_RATE;
+
+/// Default frequency of the tone, in Hz.
+pub const TONE: f32 = 100.0;
+/// Default volume of the tone, from 0 to 1.
+pub const VOLUME: f32 = 0.05;
+/// How long the tone takes to fade in or out, in milliseconds. Starting or
+/// stopping it dead clicks.
+pub const RAMP_MS: f32 = 5.0;
+/// How many frames of audio the frontend tries to keep queued: enough to
+/// ride out a slow frame without running dry.
+pub const QUEUE_FRAMES: usize = 3;
+/// The most `rate_for` will speed up or slow down the stream, as a fraction.
+/// Small enough that the change in pitch can't be heard.
+pub const MAX_RATE_ADJUST: f64 = 0.005;
+
+/// The shape of the tone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    /// A new random level every half cycle, so the tone still sets the pitch.
+    Noise,
+}
+
+/// In the order the waveform hotkey cycles through them.
+pub const WAVEFORMS: &[Waveform] = &[
+    Waveform::Square,
+    Waveform::Sine,
+    Waveform::Triangle,
+    Waveform::Noise,
+];
+
+impl Waveform {
+    /// The waveform after this one in `WAVEFORMS`.
+    pub fn next(self) -> Waveform {
+        let index = WAVEFORMS.iter().position(|&w| w == self).unwrap();
+        WAVEFORMS[(index + 1) % WAVEFORMS.len()]
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Waveform, String> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!(
+                "unknown waveform {:?} (square, sine, triangle, noise)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How the beeper sounds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeeperConfig {
+    /// Frequency in Hz.
+    pub tone: f32,
+    pub waveform: Waveform,
+    /// From 0 to 1.
+    pub volume: f32,
+    /// Silence the speakers. Recordings still have the sound.
+    pub muted: bool,
+}
+
+impl Default for BeeperConfig {
+    fn default() -> BeeperConfig {
+        BeeperConfig {
+            tone: TONE,
+            waveform: Waveform::Square,
+            volume: VOLUME,
+            muted: false,
+        }
+    }
+}
+
+/// A tone generator, switched on and off with a short fade either side.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Beeper {
+    pub config: BeeperConfig,
+    sample_rate: usize,
+    phase: f32,
+    /// Whether the tone should be sounding.
+    on: bool,
+    /// The envelope, from 0 (silent) to 1, moving towards `on`.
+    level: f32,
+    /// Noise generator state, and the level it's holding.
+    lfsr: u16,
+    noise: f32,
+}
+
+impl Beeper {
+    pub fn new(config: BeeperConfig, sample_rate: usize) -> Beeper {
+        Beeper {
+            config,
+            sample_rate,
+            phase: 0.0,
+            on: false,
+            level: 0.0,
+            lfsr: 0xACE1,
+            noise: 1.0,
+        }
+    }
+
+    /// Start or stop the tone.
+    pub fn set_on(&mut self, on: bool) {
+        self.on = on;
+    }
+
+    /// The waveform at the current phase, from -1 to 1.
+    fn wave(&mut self) -> f32 {
+        match self.config.waveform {
+            Waveform::Square => {
+                if self.phase <= 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (self.phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise,
+        }
+    }
+
+    /// Step the noise generator, a 16-bit Galois LFSR.
+    fn next_noise(&mut self) {
+        let bit = self.lfsr & 1;
+        self.lfsr >>= 1;
+        if bit == 1 {
+            self.lfsr ^= 0xB400;
+        }
+        self.noise = if self.lfsr & 1 == 1 { 1.0 } else { -1.0 };
+    }
+
+    /// Fill `out` with the tone, fading in or out as needed.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let phase_inc = self.config.tone / self.sample_rate as f32;
+        let ramp_step = 1000.0 / (RAMP_MS * self.sample_rate as f32);
+        let target = if self.on { 1.0 } else { 0.0 };
+
+        for x in out.iter_mut() {
+            if self.level < target {
+                self.level = (self.level + ramp_step).min(target);
+            } else if self.level > target {
+                self.level = (self.level - ramp_step).max(target);
+            }
+            if self.level == 0.0 {
+                // Start the next beep at the top of the wave
+                self.phase = 0.0;
+                *x = 0.0;
+                continue;
+            }
+
+            *x = self.wave() * self.config.volume * self.level;
+            let half = self.phase < 0.5;
+            self.phase = (self.phase + phase_inc) % 1.0;
+            if half != (self.phase < 0.5) {
+                self.next_noise();
+            }
+        }
+    }
+
+    /// The samples for one emulated frame, with the tone on or off.
+    pub fn frame(&mut self, on: bool) -> Vec<f32> {
+        let mut out = vec![0.0; SAMPLES_PER_FRAME];
+        self.set_on(on);
+        self.fill(&mut out);
+        out
+    }
+}
+
+/// The beeper's output in step with emulated time.
+///
+/// Each unit of emulated time (an instruction, or a machine cycle with VIP
+/// timing) is worth a fixed number of samples, so a beep lasts as long as
+/// the sound timer says no matter how fast the host runs or how big the
+/// audio buffers are. The frontend feeds the samples to a queue, and nudges
+/// `rate` with `rate_for` to keep the queue from running dry or filling up
+/// as the two clocks drift apart.
+pub struct AudioStream {
+    pub beeper: Beeper,
+    samples_per_cycle: f64,
+    /// Fraction of a sample carried over from the last call.
+    owed: f64,
+    /// Samples made per unit of time, relative to normal.
+    pub rate: f64,
+}
+
+impl AudioStream {
+    pub fn new(config: BeeperConfig, sample_rate: usize, timing: Timing) -> AudioStream {
+        AudioStream {
+            beeper: Beeper::new(config, sample_rate),
+            samples_per_cycle: sample_rate as f64 / (FRAME_RATE * timing.per_frame()) as f64,
+            owed: 0.0,
+            rate: 1.0,
+        }
+    }
+
+    /// The samples for `cycles` units of time with the tone on or off.
+    /// Silence if muted.
+    pub fn advance(&mut self, on: bool, cycles: usize) -> Vec<f32> {
+        self.owed += cycles as f64 * self.samples_per_cycle * self.rate;
+        let count = self.owed as usize;
+        self.owed -= count as f64;
+
+        let mut out = vec![0.0; count];
+        self.beeper.set_on(on);
+        self.beeper.fill(&mut out);
+        if self.beeper.config.muted {
+            for x in out.iter_mut() {
+                *x = 0.0;
+            }
+        }
+        out
+    }
+}
+
+/// Dynamic rate control: the `AudioStream::rate` that brings a queue
+/// holding `queued` samples back towards `target`.
+pub fn rate_for(queued: usize, target: usize) -> f64 {
+    let error = (target as f64 - queued as f64) / target as f64;
+    1.0 + MAX_RATE_ADJUST * error.clamp(-1.0, 1.0)
+}
+
+/// Writes mono 16-bit PCM WAV files. The header is written with zero sizes
+/// and filled in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    /// Bytes of sample data written so far.
+    len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: usize) -> io::Result<WavWriter<W>> {
+        let rate = sample_rate as u32;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&rate.to_le_bytes())?;
+        out.write_all(&(rate * 2).to_le_bytes())?; // bytes per second
+        out.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, len: 0 })
+    }
+
+    /// Write samples from -1 to 1.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.len += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    /// Fill in the sizes in the header, and return the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + self.len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headless::CYCLES_PER_FRAME;
+    use std::io::Cursor;
+
+    fn beeper(waveform: Waveform, sample_rate: usize) -> Beeper {
+        let config = BeeperConfig {
+            waveform,
+            volume: 1.0,
+            ..BeeperConfig::default()
+        };
+        let mut beeper = Beeper::new(config, sample_rate);
+        // Skip the fade in
+        beeper.set_on(true);
+        beeper.level = 1.0;
+        beeper
+    }
+
+    #[test]
+    fn waveforms() {
+        // 100 Hz at 1000 samples per second is 10 samples per cycle
+        let mut out = [0.0; 11];
+        beeper(Waveform::Square, 1000).fill(&mut out);
+        assert_eq!(&out[..7], &[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, -1.0]);
+        assert_eq!(out[10], 1.0);
+
+        beeper(Waveform::Triangle, 1000).fill(&mut out);
+        assert!((out[0] + 1.0).abs() < 1e-4);
+        assert!((out[5] - 1.0).abs() < 1e-4);
+
+        beeper(Waveform::Sine, 1000).fill(&mut out);
+        assert!(out[0].abs() < 1e-4);
+        assert!((out[5]).abs() < 1e-4);
+        assert!(out[2] > 0.9 && out[7] < -0.9);
+
+        let mut out = [0.0; 100];
+        beeper(Waveform::Noise, 1000).fill(&mut out);
+        assert!(out.iter().all(|&x| x == 1.0 || x == -1.0));
+        // Holds each level for half a cycle
+        assert!(out
+            .chunks(5)
+            .skip(1)
+            .all(|half| half.iter().all(|&x| x == half[0])));
+        assert!(out.contains(&1.0) && out.contains(&-1.0));
+    }
+
+    #[test]
+    fn envelope() {
+        let mut beeper = Beeper::new(BeeperConfig::default(), SAMPLE_RATE);
+        let ramp = (RAMP_MS * SAMPLE_RATE as f32 / 1000.0).round() as usize;
+        let on = beeper.frame(true);
+        // Square wave fading in from 0
+        assert!(on[0] > 0.0 && on[0] < VOLUME / 100.0);
+        assert!(on[ramp / 2].abs() < on[ramp + 1].abs());
+        assert_eq!(on[ramp + 1].abs(), VOLUME);
+
+        let off = beeper.frame(false);
+        assert!(off[0] != 0.0);
+        assert!(off[ramp + 1..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn frames_are_deterministic() {
+        let mut a = Beeper::new(BeeperConfig::default(), SAMPLE_RATE);
+        let mut b = Beeper::new(BeeperConfig::default(), SAMPLE_RATE);
+        assert_eq!(a.frame(true), b.frame(true));
+        assert_eq!(a.frame(true), b.frame(true));
+
+        a.frame(false);
+        // Starts over after a silence
+        assert_eq!(
+            a.frame(true),
+            Beeper::new(BeeperConfig::default(), SAMPLE_RATE).frame(true)
+        );
+    }
+
+    #[test]
+    fn stream_follows_emulated_time() {
+        let mut stream = AudioStream::new(BeeperConfig::default(), SAMPLE_RATE, Timing::default());
+        // 73.5 samples per instruction
+        assert_eq!(stream.advance(true, 1).len(), 73);
+        assert_eq!(stream.advance(true, 1).len(), 74);
+        assert_eq!(
+            stream.advance(true, CYCLES_PER_FRAME).len(),
+            SAMPLES_PER_FRAME
+        );
+
+        // The same beep however the instructions are split up
+        let mut a = AudioStream::new(BeeperConfig::default(), SAMPLE_RATE, Timing::default());
+        let mut b = AudioStream::new(BeeperConfig::default(), SAMPLE_RATE, Timing::default());
+        let mut one_by_one = Vec::new();
+        for _ in 0..CYCLES_PER_FRAME {
+            one_by_one.extend(a.advance(true, 1));
+        }
+        assert_eq!(one_by_one, b.advance(true, CYCLES_PER_FRAME));
+    }
+
+    #[test]
+    fn muted() {
+        let mut stream = AudioStream::new(BeeperConfig::default(), SAMPLE_RATE, Timing::default());
+        stream.beeper.config.muted = true;
+        let out = stream.advance(true, CYCLES_PER_FRAME);
+        assert_eq!(out.len(), SAMPLES_PER_FRAME);
+        assert!(out.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn rate_control() {
+        assert_eq!(rate_for(1000, 1000), 1.0);
+        assert!(rate_for(500, 1000) > 1.0);
+        assert!(rate_for(1500, 1000) < 1.0);
+        assert_eq!(rate_for(0, 1000), 1.0 + MAX_RATE_ADJUST);
+        assert_eq!(rate_for(100_000, 1000), 1.0 - MAX_RATE_ADJUST);
+
+        let mut stream = AudioStream::new(BeeperConfig::default(), SAMPLE_RATE, Timing::default());
+        stream.rate = rate_for(0, 1000);
+        let samples: usize = (0..100)
+            .map(|_| stream.advance(false, CYCLES_PER_FRAME).len())
+            .sum();
+        assert_eq!(samples, SAMPLES_PER_FRAME * 100 * 1005 / 1000);
+    }
+
+    #[test]
+    fn waveform_names() {
+        for &waveform in WAVEFORMS {
+            assert_eq!(waveform.to_string().parse(), Ok(waveform));
+        }
+        assert!("saw".parse::<Waveform>().is_err());
+        assert_eq!(Waveform::Noise.next(), Waveform::Square);
+    }
+
+    #[test]
+    fn wav_file() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 8000).unwrap();
+        wav.write(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &44u32.to_le_bytes());
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(&data[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(&data[40..44], &8u32.to_le_bytes());
+        assert_eq!(&data[44..], &[0, 0, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+}
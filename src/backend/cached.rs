@@ -0,0 +1,307 @@
+//! An interpreter that caches decoded basic blocks.
+//!
+//! The first time execution reaches an address, the straight-line run of
+//! instructions starting there is decoded into a block of (opcode, handler)
+//! records. After that the block is executed straight from the cache, without
+//! fetching or decoding. Blocks are thrown away when the program writes over
+//! them with `Fx33` or `Fx55`.
+
+use std::rc::Rc;
+
+use super::{bytes_written, Backend};
+use coverage;
+use CPU;
+use MEMORY_SIZE;
+
+/// Longest run of instructions decoded into one block.
+const MAX_BLOCK_LEN: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Instruction {
+    opcode: u16,
+    handler: fn(&mut CPU),
+}
+
+pub struct CachedInterpreter {
+    /// Decoded blocks, indexed by start address.
+    blocks: Vec<Option<Rc<Vec<Instruction>>>>,
+    /// How many cached blocks cover each byte of memory.
+    code: Vec<u8>,
+}
+
+/// Whether an instruction can send execution anywhere but the next
+/// instruction, or needs to hand control back to the frontend.
+fn ends_block(opcode: u16) -> bool {
+    match opcode & 0xF000 {
+        // 0nnn can run host code that goes anywhere
+        0x0000 => opcode != 0x00E0,
+        0x1000 | 0x2000 | 0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xB000 | 0xE000 => true,
+        // Fx1B skips and Fx4F waits on CHIP-8E
+        0xF000 => matches!(opcode & 0xF0FF, 0xF00A | 0xF01B | 0xF04F),
+        _ => false,
+    }
+}
+
+impl CachedInterpreter {
+    pub fn new() -> CachedInterpreter {
+        CachedInterpreter {
+            blocks: vec![None; MEMORY_SIZE],
+            code: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    /// Number of blocks currently cached.
+    pub fn cached_blocks(&self) -> usize {
+        self.blocks.iter().filter(|b| b.is_some()).count()
+    }
+
+    /// Throw away every cached block. Needed after changing `CPU::memory`
+    /// from outside the emulated program, e.g. loading a new ROM.
+    pub fn invalidate_all(&mut self) {
+        for block in self.blocks.iter_mut() {
+            *block = None;
+        }
+        for count in self.code.iter_mut() {
+            *count = 0;
+        }
+    }
+
+    /// Throw away any cached block that covers `addr`.
+    pub fn invalidate(&mut self, addr: usize) {
+        let addr = addr % MEMORY_SIZE;
+        if self.code[addr] == 0 {
+            return;
+        }
+
+        for start in addr.saturating_sub(MAX_BLOCK_LEN * 2)..=addr {
+            let covers = match self.blocks[start] {
+                Some(ref block) => addr < start + block.len() * 2,
+                None => false,
+            };
+            if covers {
+                let len = self.blocks[start].take().unwrap().len();
+                for byte in start..start + len * 2 {
+                    self.code[byte] -= 1;
+                }
+            }
+        }
+    }
+
+    /// The block starting at the PC, decoding it if it isn't cached yet.
+    /// Returns None if there isn't a whole instruction left in memory.
+    fn block_at(&mut self, cpu: &CPU) -> Option<Rc<Vec<Instruction>>> {
+        let start = cpu.pc;
+        if start + 1 >= MEMORY_SIZE {
+            return None;
+        }
+        if let Some(ref block) = self.blocks[start] {
+            return Some(block.clone());
+        }
+
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        while addr + 1 < MEMORY_SIZE && instructions.len() < MAX_BLOCK_LEN {
+            let opcode = ((cpu.memory[addr] as u16) << 8) | cpu.memory[addr + 1] as u16;
+            instructions.push(Instruction {
+                opcode,
+                handler: CPU::decode(cpu.platform, opcode),
+            });
+            if ends_block(opcode) {
+                break;
+            }
+            addr += 2;
+        }
+
+        for byte in start..start + instructions.len() * 2 {
+            self.code[byte] += 1;
+        }
+        let block = Rc::new(instructions);
+        self.blocks[start] = Some(block.clone());
+        Some(block)
+    }
+
+    /// Execute a single decoded instruction, the same way `emulate_cycle`
+    /// would.
+    fn execute(&mut self, cpu: &mut CPU, instruction: Instruction) {
+        if cpu.coverage.is_some() || cpu.smc.is_some() {
+            let pc = cpu.pc;
+            cpu.record_access(pc, coverage::EXECUTED);
+            cpu.record_access(pc + 1, coverage::EXECUTED);
+        }
+
+        let i_addr = cpu.i_addr;
+        cpu.opcode = instruction.opcode;
+        (instruction.handler)(cpu);
+
+        for addr in i_addr..i_addr + bytes_written(instruction.opcode) {
+            self.invalidate(addr);
+        }
+    }
+}
+
+impl Default for CachedInterpreter {
+    fn default() -> CachedInterpreter {
+        CachedInterpreter::new()
+    }
+}
+
+impl Backend for CachedInterpreter {
+    fn run(&mut self, cpu: &mut CPU, cycles: usize) -> usize {
+        let mut executed = 0;
+
+        while executed < cycles && cpu.fault.is_none() && !cpu.is_waiting() {
+            let start = cpu.pc;
+            let block = match self.block_at(cpu) {
+                Some(block) => block,
+                None => {
+                    // Let the interpreter apply the memory policy
+                    cpu.emulate_cycle();
+                    executed += 1;
+                    continue;
+                }
+            };
+
+            for &instruction in block.iter() {
+                self.execute(cpu, instruction);
+                executed += 1;
+
+                // Stop if we're done, have to hand control back, or the
+                // block just wrote over itself.
+                if executed == cycles
+                    || cpu.fault.is_some()
+                    || cpu.is_waiting()
+                    || self.blocks[start].is_none()
+                {
+                    break;
+                }
+            }
+        }
+
+        executed
+    }
+
+    fn name(&self) -> &'static str {
+        "cached"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::Interpreter;
+    use platform::Platform;
+    use tests::cpu_with;
+
+    /// Counts V0 up to 0x40 in a loop, storing the count with `Fx55`, then
+    /// spins forever.
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const COUNTER: [u8; 14] = [
+        0xA3, 0x00, // 200: LD I, 0x300
+        0x70, 0x01, // 202: ADD V0, 0x01
+        0xF0, 0x55, // 204: LD [I], V0
+        0x30, 0x40, // 206: SE V0, 0x40
+        0x12, 0x02, // 208: JP 0x202
+        0x81, 0x04, // 20A: ADD V1, V0
+        0x12, 0x0C, // 20C: JP 0x20C
+    ];
+
+    #[test]
+    fn matches_interpreter() {
+        let mut expected = cpu_with(Platform::Chip8, &COUNTER);
+        let mut actual = cpu_with(Platform::Chip8, &COUNTER);
+        let mut cached = CachedInterpreter::new();
+
+        assert_eq!(Interpreter.run(&mut expected, 1000), 1000);
+        assert_eq!(cached.run(&mut actual, 1000), 1000);
+
+        assert_eq!(actual.pc, expected.pc);
+        assert_eq!(actual.v_reg, expected.v_reg);
+        assert_eq!(actual.i_addr, expected.i_addr);
+        assert_eq!(actual.memory[0x300], 0x40);
+        assert_eq!(actual.v_reg[1], 0x40);
+    }
+
+    #[test]
+    fn platform_opcodes() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0x60, 0x02, // 200: LD V0, 2
+            0xF0, 0x4F, // 202: LD DT, V0 and wait
+            0xF0, 0x1B, // 204: skip V0 bytes
+            0x71, 0x01, // 206: ADD V1, 1 (skipped)
+            0x72, 0x01, // 208: ADD V2, 1
+            0xBB, 0x04, // 20A: back to 0x208
+        ];
+        let mut expected = cpu_with(Platform::Chip8, &rom);
+        expected.set_platform(Platform::Chip8E);
+        let mut actual = expected.clone();
+        let mut cached = CachedInterpreter::new();
+
+        // Fx4F waits out two frames
+        for _ in 0..3 {
+            assert_eq!(Interpreter.run(&mut expected, 50), 50);
+            assert_eq!(cached.run(&mut actual, 50), 50);
+            expected.vblank();
+            actual.vblank();
+        }
+
+        assert_eq!(actual.pc, expected.pc);
+        assert_eq!(actual.v_reg, expected.v_reg);
+        assert_eq!(actual.v_reg[1], 0);
+        assert!(actual.v_reg[2] > 0);
+    }
+
+    #[test]
+    fn stops_after_cycles() {
+        let mut cpu = cpu_with(Platform::Chip8, &COUNTER);
+        let mut cached = CachedInterpreter::new();
+
+        assert_eq!(cached.run(&mut cpu, 3), 3);
+        assert_eq!(cpu.pc, 0x206);
+        assert_eq!(cached.run(&mut cpu, 2), 2);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // Writes 0x6A, 0x99 (LD VA, 0x99) over the instruction at 0x206,
+        // then jumps back to run it again.
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0xA2, 0x06, // 200: LD I, 0x206
+            0x60, 0x6A, // 202: LD V0, 0x6A
+            0x61, 0x99, // 204: LD V1, 0x99
+            0x6A, 0x11, // 206: LD VA, 0x11 (patched)
+            0x3B, 0x00, // 208: SE VB, 0x00
+            0x12, 0x0A, // 20A: JP 0x20A
+            0x7B, 0x01, // 20C: ADD VB, 0x01
+            0xF1, 0x55, // 20E: LD [I], V1
+            0x12, 0x06, // 210: JP 0x206
+        ];
+        let mut cpu = cpu_with(Platform::Chip8, &rom);
+        let mut cached = CachedInterpreter::new();
+
+        cached.run(&mut cpu, 20);
+
+        assert_eq!(cpu.memory[0x206], 0x6A);
+        assert_eq!(cpu.memory[0x207], 0x99);
+        assert_eq!(cpu.v_reg[0xA], 0x99);
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut cpu = cpu_with(Platform::Chip8, &COUNTER);
+        let mut cached = CachedInterpreter::new();
+
+        cached.run(&mut cpu, 5);
+        assert!(cached.cached_blocks() > 0);
+
+        cached.invalidate(0x204);
+        assert!(cached.blocks[0x200].is_none());
+        assert!(cached.blocks[0x202].is_none());
+
+        cached.invalidate_all();
+        assert_eq!(cached.cached_blocks(), 0);
+        assert!(cached.code.iter().all(|&count| count == 0));
+    }
+}
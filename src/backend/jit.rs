@@ -0,0 +1,453 @@
+//! Dynamic recompiler to x86-64 machine code.
+//!
+//! Straight-line runs of register instructions (`6xkk`, `7xkk`, `8xyN`,
+//! `Annn` and `Fx1E`) are translated into a native function taking pointers
+//! to V0-VF and I. Everything else, including `Dxyn`, key waits, control flow
+//! and anything touching memory or the timers, runs on the interpreter, as
+//! does any code the program has written over. The native code only ever sees
+//! the registers, so the PC and opcode are brought up to date after each
+//! block, exactly as if the interpreter had run it.
+//!
+//! With `verify` set, every native block is checked against the interpreter
+//! in lockstep and any difference panics.
+
+use std::mem;
+use std::ptr;
+
+use libc;
+
+use super::{bytes_written, Backend, Interpreter};
+use Opcode;
+use CPU;
+use MEMORY_SIZE;
+
+/// Longest run of instructions compiled into one block.
+const MAX_BLOCK_LEN: usize = 32;
+
+/// Signature of a compiled block: `(V0-VF, I)`, using the C calling
+/// convention so the registers land in RDI and RSI.
+type NativeFn = unsafe extern "C" fn(*mut u8, *mut usize);
+
+/// A block of machine code in its own executable mapping.
+struct NativeBlock {
+    code: *mut libc::c_void,
+    size: usize,
+    /// Number of CHIP-8 instructions the block covers.
+    len: usize,
+    /// The last opcode in the block, left in `CPU::opcode` afterwards.
+    last_opcode: u16,
+}
+
+impl NativeBlock {
+    /// Copy machine code into a fresh mapping and make it executable.
+    fn new(machine_code: &[u8], len: usize, last_opcode: u16) -> Result<NativeBlock, String> {
+        let size = machine_code.len();
+        unsafe {
+            let code = libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if code == libc::MAP_FAILED {
+                return Err("mmap failed".to_string());
+            }
+            // Own the mapping straight away so it gets unmapped on error
+            let block = NativeBlock {
+                code,
+                size,
+                len,
+                last_opcode,
+            };
+            ptr::copy_nonoverlapping(machine_code.as_ptr(), code as *mut u8, size);
+            if libc::mprotect(code, size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return Err("mprotect failed".to_string());
+            }
+            Ok(block)
+        }
+    }
+
+    fn call(&self, cpu: &mut CPU) {
+        unsafe {
+            let function: NativeFn = mem::transmute(self.code);
+            function(cpu.v_reg.as_mut_ptr(), &mut cpu.i_addr);
+        }
+    }
+}
+
+impl Drop for NativeBlock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.code, self.size);
+        }
+    }
+}
+
+/// Append the machine code for one instruction, or return false if it can't
+/// be compiled.
+///
+/// RDI points at V0-VF and RSI at I. AL and CL are used as scratch.
+fn emit(code: &mut Vec<u8>, opcode: u16) -> bool {
+    let x = opcode.x() as u8;
+    let y = opcode.y() as u8;
+    let kk = opcode.kk();
+
+    match opcode & 0xF000 {
+        // mov byte [rdi+x], kk
+        0x6000 => code.extend_from_slice(&[0xC6, 0x47, x, kk]),
+        // add byte [rdi+x], kk
+        0x7000 => code.extend_from_slice(&[0x80, 0x47, x, kk]),
+        0x8000 => match opcode.n() {
+            // mov al, [rdi+y]; then mov/or/and/xor [rdi+x], al
+            0x0 => code.extend_from_slice(&[0x8A, 0x47, y, 0x88, 0x47, x]),
+            0x1 => code.extend_from_slice(&[0x8A, 0x47, y, 0x08, 0x47, x]),
+            0x2 => code.extend_from_slice(&[0x8A, 0x47, y, 0x20, 0x47, x]),
+            0x3 => code.extend_from_slice(&[0x8A, 0x47, y, 0x30, 0x47, x]),
+            // mov al, [rdi+x]; add al, [rdi+y]; setc cl
+            0x4 => code.extend_from_slice(&[0x8A, 0x47, x, 0x02, 0x47, y, 0x0F, 0x92, 0xC1]),
+            // mov al, [rdi+x]; sub al, [rdi+y]; setnc cl
+            0x5 => code.extend_from_slice(&[0x8A, 0x47, x, 0x2A, 0x47, y, 0x0F, 0x93, 0xC1]),
+            // mov al, [rdi+y]; sub al, [rdi+x]; setnc cl
+            0x7 => code.extend_from_slice(&[0x8A, 0x47, y, 0x2A, 0x47, x, 0x0F, 0x93, 0xC1]),
+            // mov al, [rdi+x]; mov cl, al; and cl, 0x01; shr byte [rdi+x], 1;
+            // mov [rdi+15], cl
+            0x6 => code.extend_from_slice(&[
+                0x8A, 0x47, x, 0x88, 0xC1, 0x80, 0xE1, 0x01, 0xD0, 0x6F, x, 0x88, 0x4F, 0x0F,
+            ]),
+            // mov al, [rdi+x]; mov cl, al; shr cl, 7; shl byte [rdi+x], 1;
+            // mov [rdi+15], cl
+            0xE => code.extend_from_slice(&[
+                0x8A, 0x47, x, 0x88, 0xC1, 0xC0, 0xE9, 0x07, 0xD0, 0x67, x, 0x88, 0x4F, 0x0F,
+            ]),
+            _ => return false,
+        },
+        // mov qword [rsi], nnn
+        0xA000 => {
+            code.extend_from_slice(&[0x48, 0xC7, 0x06]);
+            code.extend_from_slice(&(opcode.nnn() as u32).to_le_bytes());
+        }
+        // movzx eax, byte [rdi+x]; add [rsi], rax
+        0xF000 if opcode.kk() == 0x1E => {
+            code.extend_from_slice(&[0x0F, 0xB6, 0x47, x, 0x48, 0x01, 0x06]);
+        }
+        _ => return false,
+    }
+
+    // The arithmetic ops store the flag after the result, like the
+    // interpreter does, so the flag wins when x is F: mov [rdi+x], al;
+    // mov [rdi+15], cl
+    if opcode & 0xF000 == 0x8000 {
+        if let 0x4 | 0x5 | 0x7 = opcode.n() {
+            code.extend_from_slice(&[0x88, 0x47, x, 0x88, 0x4F, 0x0F]);
+        }
+    }
+    true
+}
+
+pub struct JitBackend {
+    /// Compiled blocks, indexed by start address. `None` inside means we
+    /// tried and there was nothing to compile.
+    blocks: Vec<Option<Option<NativeBlock>>>,
+    /// Addresses the program has written to. Blocks covering them are left
+    /// to the interpreter.
+    dirty: Vec<bool>,
+    /// Check every native block against the interpreter.
+    pub verify: bool,
+}
+
+impl JitBackend {
+    pub fn new() -> JitBackend {
+        JitBackend {
+            blocks: (0..MEMORY_SIZE).map(|_| None).collect(),
+            dirty: vec![false; MEMORY_SIZE],
+            verify: false,
+        }
+    }
+
+    /// Number of blocks compiled to native code.
+    pub fn compiled_blocks(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| matches!(**b, Some(Some(_))))
+            .count()
+    }
+
+    /// Throw away every compiled block and forget which code was written
+    /// to. Needed after changing `CPU::memory` from outside the program.
+    pub fn invalidate_all(&mut self) {
+        for block in self.blocks.iter_mut() {
+            *block = None;
+        }
+        for dirty in self.dirty.iter_mut() {
+            *dirty = false;
+        }
+    }
+
+    /// Mark `addr` as written by the program, throwing away any block that
+    /// covers it.
+    fn mark_dirty(&mut self, addr: usize) {
+        let addr = addr % MEMORY_SIZE;
+        // Blocks never cover dirty addresses, so only the first write counts
+        if self.dirty[addr] {
+            return;
+        }
+        self.dirty[addr] = true;
+        for start in addr.saturating_sub(MAX_BLOCK_LEN * 2)..=addr {
+            self.blocks[start] = None;
+        }
+    }
+
+    /// Compile the run of instructions starting at `start`.
+    fn compile(&self, cpu: &CPU, start: usize) -> Option<NativeBlock> {
+        let mut code = Vec::new();
+        let mut len = 0;
+        let mut last_opcode = 0;
+
+        let mut addr = start;
+        while addr + 1 < MEMORY_SIZE && len < MAX_BLOCK_LEN {
+            if self.dirty[addr] || self.dirty[addr + 1] {
+                break;
+            }
+            let opcode = ((cpu.memory[addr] as u16) << 8) | cpu.memory[addr + 1] as u16;
+            if !emit(&mut code, opcode) {
+                break;
+            }
+            len += 1;
+            last_opcode = opcode;
+            addr += 2;
+        }
+
+        if len == 0 {
+            return None;
+        }
+        code.push(0xC3); // ret
+        NativeBlock::new(&code, len, last_opcode).ok()
+    }
+
+    /// Run one instruction on the interpreter, noting any memory it writes.
+    fn interpret(&mut self, cpu: &mut CPU) {
+        let i_addr = cpu.i_addr;
+        cpu.emulate_cycle();
+        if cpu.fault.is_none() {
+            for addr in i_addr..i_addr + bytes_written(cpu.opcode) {
+                self.mark_dirty(addr);
+            }
+        }
+    }
+
+    /// Run a compiled block and bring the rest of the CPU up to date.
+    fn execute(block: &NativeBlock, cpu: &mut CPU) {
+        block.call(cpu);
+        cpu.pc += block.len * 2;
+        cpu.opcode = block.last_opcode;
+    }
+
+    /// Run a compiled block, and the interpreter on a copy of the CPU, and
+    /// make sure they agree.
+    fn execute_verified(block: &NativeBlock, cpu: &mut CPU) {
+        let start = cpu.pc;
+        let mut expected = cpu.clone();
+        Interpreter.run(&mut expected, block.len);
+        JitBackend::execute(block, cpu);
+
+        if cpu.v_reg != expected.v_reg
+            || cpu.i_addr != expected.i_addr
+            || cpu.pc != expected.pc
+            || cpu.opcode != expected.opcode
+            || cpu.delay_timer != expected.delay_timer
+            || cpu.sound_timer != expected.sound_timer
+        {
+            panic!(
+                "JIT block at 0x{:03X} diverged from the interpreter:\n\
+                 jit:         V={:02X?} I=0x{:03X} PC=0x{:03X}\n\
+                 interpreter: V={:02X?} I=0x{:03X} PC=0x{:03X}",
+                start, cpu.v_reg, cpu.i_addr, cpu.pc, expected.v_reg, expected.i_addr, expected.pc
+            );
+        }
+    }
+}
+
+impl Default for JitBackend {
+    fn default() -> JitBackend {
+        JitBackend::new()
+    }
+}
+
+impl Backend for JitBackend {
+    fn run(&mut self, cpu: &mut CPU, cycles: usize) -> usize {
+        let mut executed = 0;
+
+        while executed < cycles && cpu.fault.is_none() && !cpu.is_waiting() {
+            // Native code doesn't report memory accesses, so leave tracking
+            // to the interpreter.
+            let tracking = cpu.coverage.is_some() || cpu.smc.is_some();
+            let start = cpu.pc;
+
+            if !tracking && start < MEMORY_SIZE && self.blocks[start].is_none() {
+                let block = self.compile(cpu, start);
+                self.blocks[start] = Some(block);
+            }
+
+            let native = match self.blocks.get(start) {
+                Some(&Some(Some(ref block))) if !tracking && block.len <= cycles - executed => {
+                    Some(block)
+                }
+                _ => None,
+            };
+            match native {
+                Some(block) => {
+                    if self.verify {
+                        JitBackend::execute_verified(block, cpu);
+                    } else {
+                        JitBackend::execute(block, cpu);
+                    }
+                    executed += block.len;
+                }
+                None => {
+                    self.interpret(cpu);
+                    executed += 1;
+                }
+            }
+        }
+
+        executed
+    }
+
+    fn step_len(&mut self, cpu: &CPU) -> usize {
+        let start = cpu.pc;
+        if cpu.coverage.is_some() || cpu.smc.is_some() || start >= MEMORY_SIZE {
+            return 1;
+        }
+        if self.blocks[start].is_none() {
+            let block = self.compile(cpu, start);
+            self.blocks[start] = Some(block);
+        }
+        match self.blocks[start] {
+            Some(Some(ref block)) => block.len,
+            _ => 1,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "jit"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lockstep::{Lockstep, Machine};
+    use platform::Platform;
+    use rand::prelude::{Rng, SeedableRng, SmallRng};
+    use tests::cpu_with;
+
+    /// Every compilable instruction, with random registers, against the
+    /// interpreter.
+    #[test]
+    fn matches_interpreter() {
+        let mut rng = SmallRng::from_seed([0x1B; 16]);
+        let mut opcodes = vec![0x6A5B, 0x7FFF, 0x7A80, 0xA123, 0xFA1E, 0xFF1E];
+        for n in &[0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE] {
+            for &(x, y) in &[(0x1, 0x2), (0xF, 0x3), (0x3, 0xF), (0x4, 0x4), (0xF, 0xF)] {
+                opcodes.push(0x8000 | (x << 8) | (y << 4) | n);
+            }
+        }
+
+        for &opcode in &opcodes {
+            for _ in 0..50 {
+                let rom = [(opcode >> 8) as u8, opcode as u8];
+                let mut cpu = cpu_with(Platform::Chip8, &rom);
+                rng.fill(&mut cpu.v_reg);
+                cpu.i_addr = rng.gen_range(0, 0x1000);
+                let start = format!(
+                    "{:04X} with V={:02X?} I=0x{:03X}",
+                    opcode, cpu.v_reg, cpu.i_addr
+                );
+                let mut expected = cpu.clone();
+                Interpreter.run(&mut expected, 1);
+
+                let mut jit = JitBackend::new();
+                assert_eq!(jit.run(&mut cpu, 1), 1, "{}", start);
+                assert_eq!(jit.compiled_blocks(), 1, "{}", start);
+                assert_eq!(cpu.v_reg, expected.v_reg, "{}", start);
+                assert_eq!(cpu.i_addr, expected.i_addr, "{}", start);
+                assert_eq!(cpu.pc, expected.pc, "{}", start);
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_interpreter() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0x60, 0x05, // 200: LD V0, 0x05
+            0x71, 0x01, // 202: ADD V1, 0x01
+            0x81, 0x04, // 204: ADD V1, V0
+            0xD0, 0x05, // 206: DRW V0, V0, 5
+            0x30, 0x05, // 208: SE V0, 0x05
+            0x12, 0x08, // 20A: JP 0x20A
+            0x12, 0x02, // 20C: JP 0x202
+        ];
+        let mut expected = cpu_with(Platform::Chip8, &rom);
+        let mut actual = cpu_with(Platform::Chip8, &rom);
+        let mut jit = JitBackend::new();
+        jit.verify = true;
+
+        assert_eq!(Interpreter.run(&mut expected, 200), 200);
+        assert_eq!(jit.run(&mut actual, 200), 200);
+
+        assert_eq!(actual.v_reg, expected.v_reg);
+        assert_eq!(actual.pc, expected.pc);
+        assert_eq!(&actual.display[..], &expected.display[..]);
+    }
+
+    #[test]
+    fn lockstep_runs_native_blocks() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0x60, 0x05, // 200: LD V0, 0x05
+            0x71, 0x01, // 202: ADD V1, 0x01
+            0x81, 0x04, // 204: ADD V1, V0
+            0x12, 0x02, // 206: JP 0x202
+        ];
+        let machines = |jit: JitBackend| {
+            Lockstep::new(
+                Machine::new(cpu_with(Platform::Chip8, &rom), Box::new(Interpreter)),
+                Machine::new(cpu_with(Platform::Chip8, &rom), Box::new(jit)),
+            )
+        };
+        assert_eq!(machines(JitBackend::new()).run(100), Ok(100));
+
+        // A block that does nothing in place of the first three instructions
+        let mut jit = JitBackend::new();
+        jit.blocks[0x200] = Some(Some(NativeBlock::new(&[0xC3], 3, 0x8104).unwrap()));
+        let divergence = machines(jit).run(100).unwrap_err();
+        assert_eq!((divergence.step, divergence.pc), (0, 0x200));
+        assert_eq!(divergence.differences[0], "V0: 0x05 != 0x00");
+    }
+
+    #[test]
+    fn self_modified_code_is_interpreted() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0x6A, 0x01, // 200: LD VA, 0x01 (patched to LD VA, 0x77)
+            0xA2, 0x00, // 202: LD I, 0x200
+            0x60, 0x6A, // 204: LD V0, 0x6A
+            0x61, 0x77, // 206: LD V1, 0x77
+            0xF1, 0x55, // 208: LD [I], V1
+            0x12, 0x00, // 20A: JP 0x200
+        ];
+        let mut cpu = cpu_with(Platform::Chip8, &rom);
+        let mut jit = JitBackend::new();
+        jit.verify = true;
+
+        jit.run(&mut cpu, 7);
+
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.v_reg[0xA], 0x77);
+        assert!(jit.dirty[0x200]);
+        assert!(jit.blocks[0x200].is_none() || jit.blocks[0x200].as_ref().unwrap().is_none());
+    }
+}
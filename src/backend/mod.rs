@@ -0,0 +1,90 @@
+//! Execution backends.
+//!
+//! A backend runs instructions on a `CPU`. They all have to leave the CPU in
+//! exactly the state the plain interpreter would, they only differ in how
+//! fast they get there.
+
+pub mod cached;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub mod jit;
+
+pub use self::cached::CachedInterpreter;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub use self::jit::JitBackend;
+
+use Opcode;
+use CPU;
+
+pub trait Backend {
+    /// Execute up to `cycles` instructions, stopping early after a fault or
+    /// when the CPU starts waiting for a key or the next frame. Returns how
+    /// many instructions were executed.
+    fn run(&mut self, cpu: &mut CPU, cycles: usize) -> usize;
+
+    /// How many instructions from the PC this backend runs in one go, so
+    /// lockstep testing can compare it with another backend at the points
+    /// where it's been brought up to date. Most backends go one instruction
+    /// at a time.
+    fn step_len(&mut self, _cpu: &CPU) -> usize {
+        1
+    }
+
+    /// Short name used on the command line and in reports.
+    fn name(&self) -> &'static str;
+}
+
+/// The plain fetch/decode/execute interpreter, one `emulate_cycle` at a time.
+pub struct Interpreter;
+
+impl Backend for Interpreter {
+    fn run(&mut self, cpu: &mut CPU, cycles: usize) -> usize {
+        for executed in 0..cycles {
+            if cpu.fault.is_some() || cpu.is_waiting() {
+                return executed;
+            }
+            cpu.emulate_cycle();
+        }
+        cycles
+    }
+
+    fn name(&self) -> &'static str {
+        "interpreter"
+    }
+}
+
+/// How many bytes at I an instruction writes to, so backends that cache
+/// code know what to throw away.
+fn bytes_written(opcode: u16) -> usize {
+    // Only CHIP-8E's 5xy2 writes, but throwing away too much is harmless
+    if opcode & 0xF00F == 0x5002 {
+        return (opcode.y() + 1).saturating_sub(opcode.x());
+    }
+    match opcode & 0xF0FF {
+        0xF033 => 3,
+        0xF055 => opcode.x() + 1,
+        _ => 0,
+    }
+}
+
+/// Names of the backends available in this build.
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+pub const NAMES: &[&str] = &["interpreter", "cached"];
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub const NAMES: &[&str] = &["interpreter", "cached", "jit", "jit-verify"];
+
+/// Create a backend from its name.
+pub fn by_name(name: &str) -> Result<Box<dyn Backend>, String> {
+    match name {
+        "interpreter" => Ok(Box::new(Interpreter)),
+        "cached" => Ok(Box::new(CachedInterpreter::new())),
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        "jit" => Ok(Box::new(JitBackend::new())),
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        "jit-verify" => {
+            let mut jit = JitBackend::new();
+            jit.verify = true;
+            Ok(Box::new(jit))
+        }
+        _ => Err(format!("unknown backend {:?} ({})", name, NAMES.join(", "))),
+    }
+}
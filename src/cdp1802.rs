@@ -0,0 +1,469 @@
+//! The RCA CDP1802, the CPU in the COSMAC VIP.
+//!
+//! The 1802 has sixteen 16-bit registers, any of which can be the program
+//! counter (`P` picks which) or the index register for memory operands
+//! (`X` picks which), an 8-bit accumulator `D` with a carry flag `DF`, and
+//! an output line `Q`. Most instructions take two machine cycles, long
+//! branches and skips three. Memory and I/O go through a `Bus`, so the same
+//! core can be wired into the VIP or a test harness.
+
+/// What the 1802 is connected to.
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+    /// `OUT n`, for `port` 1 to 7.
+    fn output(&mut self, port: u8, value: u8);
+    /// `INP n`, for `port` 1 to 7.
+    fn input(&mut self, port: u8) -> u8;
+    /// Whether external flag line `EF1` to `EF4` (`n` from 1 to 4) is
+    /// asserted.
+    fn flag(&self, n: u8) -> bool;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cdp1802 {
+    pub d: u8,
+    pub df: bool,
+    /// Scratchpad registers R0 to RF.
+    pub r: [u16; 16],
+    /// Which register is the program counter.
+    pub p: u8,
+    /// Which register is the index register.
+    pub x: u8,
+    /// X and P saved by an interrupt or `MARK`.
+    pub t: u8,
+    /// Interrupts enabled.
+    pub ie: bool,
+    pub q: bool,
+    /// Stopped by `IDL` until the next DMA or interrupt.
+    pub idle: bool,
+}
+
+impl Cdp1802 {
+    /// The state after a reset: P, X and R0 zeroed, interrupts enabled.
+    pub fn new() -> Cdp1802 {
+        Cdp1802 {
+            ie: true,
+            ..Cdp1802::default()
+        }
+    }
+
+    /// Read the byte at R(P) and step past it.
+    fn immediate<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let pc = self.r[self.p as usize];
+        self.r[self.p as usize] = pc.wrapping_add(1);
+        bus.read(pc)
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    /// `D + value + carry`, setting DF to the carry out.
+    fn add(&mut self, value: u8, carry: bool) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    /// `a - b - borrow`, setting DF when there's no borrow out.
+    fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    /// Condition tested by short branch `3N` and long branch `CN`
+    /// instructions with the low three bits of N; the high bit inverts it.
+    fn condition<B: Bus>(&self, bus: &B, n: u8) -> bool {
+        let condition = match n & 7 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            flag => bus.flag(flag - 3),
+        };
+        condition != (n & 8 != 0)
+    }
+
+    /// Execute one instruction. Returns how many machine cycles it took.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> usize {
+        if self.idle {
+            return 1;
+        }
+
+        let opcode = self.immediate(bus);
+        let (i, n) = (opcode >> 4, opcode & 0xF);
+        let rn = n as usize;
+        match i {
+            0x0 if n == 0 => self.idle = true,
+            // LDN
+            0x0 => self.d = bus.read(self.r[rn]),
+            // INC
+            0x1 => self.r[rn] = self.r[rn].wrapping_add(1),
+            // DEC
+            0x2 => self.r[rn] = self.r[rn].wrapping_sub(1),
+            // Short branches: the target replaces the low byte of R(P)
+            0x3 => {
+                let pc = self.r[self.p as usize];
+                let take = match n {
+                    // SKP: skip the next byte
+                    0x8 => false,
+                    _ => self.condition(bus, n),
+                };
+                self.r[self.p as usize] = if take {
+                    (pc & 0xFF00) | bus.read(pc) as u16
+                } else {
+                    pc.wrapping_add(1)
+                };
+            }
+            // LDA
+            0x4 => {
+                self.d = bus.read(self.r[rn]);
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            }
+            // STR
+            0x5 => bus.write(self.r[rn], self.d),
+            // IRX
+            0x6 if n == 0 => self.r[self.x as usize] = self.rx().wrapping_add(1),
+            // OUT
+            0x6 if n < 8 => {
+                let value = bus.read(self.rx());
+                self.r[self.x as usize] = self.rx().wrapping_add(1);
+                bus.output(n, value);
+            }
+            // Unused on the 1802
+            0x6 if n == 8 => {}
+            // INP
+            0x6 => {
+                let value = bus.input(n - 8);
+                bus.write(self.rx(), value);
+                self.d = value;
+            }
+            0x7 => self.step_7(bus, n),
+            // GLO, GHI, PLO, PHI
+            0x8 => self.d = self.r[rn] as u8,
+            0x9 => self.d = (self.r[rn] >> 8) as u8,
+            0xA => self.r[rn] = (self.r[rn] & 0xFF00) | self.d as u16,
+            0xB => self.r[rn] = (self.r[rn] & 0x00FF) | (self.d as u16) << 8,
+            0xC => return self.step_long(bus, n),
+            // SEP, SEX
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => self.step_f(bus, n),
+        }
+        2
+    }
+
+    /// The `7N` instructions: returns, stack operations, arithmetic with
+    /// carry, and Q.
+    fn step_7<B: Bus>(&mut self, bus: &mut B, n: u8) {
+        match n {
+            // RET, DIS
+            0x0 | 0x1 => {
+                let value = bus.read(self.rx());
+                self.r[self.x as usize] = self.rx().wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = n == 0;
+            }
+            // LDXA
+            0x2 => {
+                self.d = bus.read(self.rx());
+                self.r[self.x as usize] = self.rx().wrapping_add(1);
+            }
+            // STXD
+            0x3 => {
+                bus.write(self.rx(), self.d);
+                self.r[self.x as usize] = self.rx().wrapping_sub(1);
+            }
+            // ADC, SDB, SMB
+            0x4 => {
+                let value = bus.read(self.rx());
+                let carry = self.df;
+                self.add(value, carry);
+            }
+            0x5 => {
+                let value = bus.read(self.rx());
+                let (d, df) = (self.d, self.df);
+                self.subtract(value, d, !df);
+            }
+            0x7 => {
+                let value = bus.read(self.rx());
+                let (d, df) = (self.d, self.df);
+                self.subtract(d, value, !df);
+            }
+            // SHRC, SHLC: rotate through DF
+            0x6 => {
+                let carry = self.d & 1 != 0;
+                self.d = self.d >> 1 | (self.df as u8) << 7;
+                self.df = carry;
+            }
+            0xE => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as u8;
+                self.df = carry;
+            }
+            // SAV
+            0x8 => bus.write(self.rx(), self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            // REQ, SEQ
+            0xA => self.q = false,
+            0xB => self.q = true,
+            // ADCI, SDBI, SMBI
+            0xC => {
+                let value = self.immediate(bus);
+                let carry = self.df;
+                self.add(value, carry);
+            }
+            0xD => {
+                let value = self.immediate(bus);
+                let (d, df) = (self.d, self.df);
+                self.subtract(value, d, !df);
+            }
+            _ => {
+                let value = self.immediate(bus);
+                let (d, df) = (self.d, self.df);
+                self.subtract(d, value, !df);
+            }
+        }
+    }
+
+    /// The `CN` long branches and skips, which take three machine cycles.
+    fn step_long<B: Bus>(&mut self, bus: &mut B, n: u8) -> usize {
+        let pc = self.r[self.p as usize];
+        // Long skips test their condition the other way round from
+        // branches: LSNQ (C5) skips when Q is off, like LBNQ (C9) branches
+        let skip = match n {
+            // NOP
+            0x4 => return 3,
+            // LSIE
+            0xC => Some(self.ie),
+            0x5..=0x7 | 0xD..=0xF => Some(self.condition(bus, (n & 3) | (!n & 8))),
+            // LSKP
+            0x8 => Some(true),
+            _ => None,
+        };
+        self.r[self.p as usize] = match skip {
+            Some(true) => pc.wrapping_add(2),
+            Some(false) => pc,
+            None if self.condition(bus, n) => {
+                (bus.read(pc) as u16) << 8 | bus.read(pc.wrapping_add(1)) as u16
+            }
+            None => pc.wrapping_add(2),
+        };
+        3
+    }
+
+    /// The `FN` instructions: logic and arithmetic on D, with M(R(X)) for
+    /// `F0` to `F7` and an immediate byte for `F8` to `FF`.
+    fn step_f<B: Bus>(&mut self, bus: &mut B, n: u8) {
+        let value = match n {
+            0x6 | 0xE => 0,
+            0x0..=0x7 => bus.read(self.rx()),
+            _ => self.immediate(bus),
+        };
+        let d = self.d;
+        match n & 7 {
+            // LDX, LDI
+            0x0 => self.d = value,
+            // OR, AND, XOR
+            0x1 => self.d |= value,
+            0x2 => self.d &= value,
+            0x3 => self.d ^= value,
+            // ADD
+            0x4 => self.add(value, false),
+            // SD: M - D
+            0x5 => self.subtract(value, d, false),
+            // SM: D - M
+            0x7 => self.subtract(d, value, false),
+            // SHR, SHL
+            _ if n == 0x6 => {
+                self.df = d & 1 != 0;
+                self.d = d >> 1;
+            }
+            _ => {
+                self.df = d & 0x80 != 0;
+                self.d = d << 1;
+            }
+        }
+    }
+
+    /// Take an interrupt, if they're enabled: save X and P in T and jump
+    /// to R1 with R2 as the index register. Returns the machine cycles
+    /// taken.
+    pub fn interrupt(&mut self) -> usize {
+        if !self.ie {
+            return 0;
+        }
+        self.t = self.x << 4 | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    /// One DMA output cycle: the byte at R0 goes out to the bus, and R0
+    /// steps on. Takes a machine cycle.
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestBus {
+        memory: Vec<u8>,
+        outputs: Vec<(u8, u8)>,
+        flags: [bool; 4],
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.memory[addr as usize]
+        }
+
+        fn write(&mut self, addr: u16, value: u8) {
+            self.memory[addr as usize] = value;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.outputs.push((port, value));
+        }
+
+        fn input(&mut self, port: u8) -> u8 {
+            0x10 + port
+        }
+
+        fn flag(&self, n: u8) -> bool {
+            self.flags[n as usize - 1]
+        }
+    }
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, TestBus) {
+        let mut bus = TestBus {
+            memory: vec![0; 0x10000],
+            outputs: Vec::new(),
+            flags: [false; 4],
+        };
+        bus.memory[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        for _ in 0..steps {
+            cpu.step(&mut bus);
+        }
+        (cpu, bus)
+    }
+
+    #[test]
+    fn arithmetic() {
+        // LDI FF, ADI 02: carry out
+        let (cpu, _) = run(&[0xF8, 0xFF, 0xFC, 0x02], 2);
+        assert_eq!((cpu.d, cpu.df), (0x01, true));
+
+        // LDI 05, SMI 07: borrow, so DF is clear
+        let (cpu, _) = run(&[0xF8, 0x05, 0xFF, 0x07], 2);
+        assert_eq!((cpu.d, cpu.df), (0xFE, false));
+
+        // LDI 05, SDI 07: 07 - 05
+        let (cpu, _) = run(&[0xF8, 0x05, 0xFD, 0x07], 2);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+
+        // LDI 81, SHL, then SHLC rotates the carry back in
+        let (cpu, _) = run(&[0xF8, 0x81, 0xFE, 0x7E], 3);
+        assert_eq!((cpu.d, cpu.df), (0x05, false));
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        // LDI 12, PHI 3, LDI 34, PLO 3, SEX 3, LDI AA, STXD, INC 3, LDX
+        let program = [
+            0xF8, 0x12, 0xB3, 0xF8, 0x34, 0xA3, 0xE3, 0xF8, 0xAA, 0x73, 0x13, 0xF0,
+        ];
+        let (cpu, bus) = run(&program, 9);
+        assert_eq!(bus.memory[0x1234], 0xAA);
+        assert_eq!(cpu.r[3], 0x1234);
+        assert_eq!(cpu.d, 0xAA);
+        assert_eq!(cpu.r[0], program.len() as u16);
+    }
+
+    #[test]
+    fn branches() {
+        // LDI 00, BZ 06, ..., 06: LBR 1234
+        let (cpu, _) = run(&[0xF8, 0x00, 0x32, 0x06, 0, 0, 0xC0, 0x12, 0x34], 3);
+        assert_eq!(cpu.r[0], 0x1234);
+
+        // LDI 01, BZ 06 falls through; LSNZ skips
+        let (cpu, _) = run(&[0xF8, 0x01, 0x32, 0x06, 0xC6, 0, 0, 0xF8, 0x09], 4);
+        assert_eq!(cpu.d, 0x09);
+
+        // B3 follows EF3
+        let mut bus = TestBus {
+            memory: vec![0; 0x10000],
+            outputs: Vec::new(),
+            flags: [false, false, true, false],
+        };
+        bus.memory[..2].copy_from_slice(&[0x36, 0x40]);
+        let mut cpu = Cdp1802::new();
+        assert_eq!(cpu.step(&mut bus), 2);
+        assert_eq!(cpu.r[0], 0x40);
+    }
+
+    #[test]
+    fn subroutines() {
+        // SEP 3 to a routine at R3, which does SEQ and SEP 0 back
+        let mut program = vec![0xF8, 0x10, 0xA3, 0xD3, 0xF8, 0x42];
+        program.resize(0x10, 0);
+        program.extend_from_slice(&[0x7B, 0xD0]);
+        let (cpu, _) = run(&program, 6);
+        assert!(cpu.q);
+        assert_eq!(cpu.p, 0);
+        assert_eq!(cpu.d, 0x42);
+
+        // MARK saves X and P, RET restores them
+        // LDI 80, PLO 2, SEX 5, MARK, INC 2, SEX 2, RET
+        let program = [0xF8, 0x80, 0xA2, 0xE5, 0x79, 0x12, 0xE2, 0x70];
+        let (cpu, bus) = run(&program, 7);
+        assert_eq!(bus.memory[0x80], 0x50);
+        assert_eq!((cpu.x, cpu.p), (5, 0));
+        assert!(cpu.ie);
+    }
+
+    #[test]
+    fn io() {
+        // SEX 0, OUT 2 with the 7F after it, then INP 1 into the byte after
+        // that
+        let (cpu, bus) = run(&[0xE0, 0x62, 0x7F, 0x69], 3);
+        assert_eq!(bus.outputs, vec![(2, 0x7F)]);
+        assert_eq!(cpu.d, 0x11);
+        assert_eq!(bus.memory[4], 0x11);
+    }
+
+    #[test]
+    fn interrupts_and_dma() {
+        let (mut cpu, mut bus) = run(&[0xE5, 0x00], 2);
+        assert!(cpu.idle);
+        assert_eq!(cpu.step(&mut bus), 1);
+
+        assert_eq!(cpu.interrupt(), 1);
+        assert!(!cpu.idle && !cpu.ie);
+        assert_eq!((cpu.t, cpu.p, cpu.x), (0x50, 1, 2));
+        assert_eq!(cpu.interrupt(), 0);
+
+        bus.memory[0x300] = 0x5A;
+        cpu.r[0] = 0x300;
+        assert_eq!(cpu.dma_out(&mut bus), 0x5A);
+        assert_eq!(cpu.r[0], 0x301);
+    }
+}
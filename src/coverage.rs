@@ -0,0 +1,233 @@
+//! Coverage tracking over CHIP-8 memory.
+//!
+//! Records which bytes were fetched as instructions, read as data (by `Dxyn`
+//! and `Fx65`) or written (by `Fx33` and `Fx55`). Maps from several runs can
+//! be merged and exported as JSON or as an annotated disassembly listing.
+
+use disasm::{disassemble, opcode_at};
+use MEMORY_SIZE;
+
+/// The byte was fetched as part of an instruction.
+pub const EXECUTED: u8 = 0b001;
+/// The byte was read as data.
+pub const READ: u8 = 0b010;
+/// The byte was written to.
+pub const WRITTEN: u8 = 0b100;
+
+/// JSON keys for each kind of access, in the order they're exported.
+const JSON_KEYS: [(&str, u8); 3] = [("executed", EXECUTED), ("read", READ), ("written", WRITTEN)];
+
+/// Per-address access flags for the whole of memory.
+#[derive(Clone)]
+pub struct Coverage {
+    flags: [u8; MEMORY_SIZE],
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            flags: [0; MEMORY_SIZE],
+        }
+    }
+
+    /// Record an access to `addr`. Out of range addresses are ignored.
+    pub fn mark(&mut self, addr: usize, flag: u8) {
+        if addr < MEMORY_SIZE {
+            self.flags[addr] |= flag;
+        }
+    }
+
+    /// Get the access flags for `addr`.
+    pub fn flags(&self, addr: usize) -> u8 {
+        if addr < MEMORY_SIZE {
+            self.flags[addr]
+        } else {
+            0
+        }
+    }
+
+    pub fn is_executed(&self, addr: usize) -> bool {
+        self.flags(addr) & EXECUTED != 0
+    }
+
+    pub fn is_read(&self, addr: usize) -> bool {
+        self.flags(addr) & READ != 0
+    }
+
+    pub fn is_written(&self, addr: usize) -> bool {
+        self.flags(addr) & WRITTEN != 0
+    }
+
+    /// Number of addresses that have any of the bits in `flag` set.
+    pub fn count(&self, flag: u8) -> usize {
+        self.flags.iter().filter(|f| *f & flag != 0).count()
+    }
+
+    /// Combine the accesses recorded in another run into this one.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (mine, theirs) in self.flags.iter_mut().zip(other.flags.iter()) {
+            *mine |= *theirs;
+        }
+    }
+
+    /// Export as a JSON object holding a list of addresses per access kind.
+    pub fn to_json(&self) -> String {
+        let lists: Vec<String> = JSON_KEYS
+            .iter()
+            .map(|&(key, flag)| {
+                let addrs: Vec<String> = (0..MEMORY_SIZE)
+                    .filter(|&addr| self.flags[addr] & flag != 0)
+                    .map(|addr| addr.to_string())
+                    .collect();
+                format!("  \"{}\": [{}]", key, addrs.join(", "))
+            })
+            .collect();
+
+        format!("{{\n{}\n}}\n", lists.join(",\n"))
+    }
+
+    /// Parse a map previously written by `to_json`.
+    pub fn from_json(json: &str) -> Result<Coverage, String> {
+        let mut coverage = Coverage::new();
+
+        for &(key, flag) in JSON_KEYS.iter() {
+            let quoted = format!("\"{}\"", key);
+            let start = json
+                .find(&quoted)
+                .ok_or_else(|| format!("missing key {}", quoted))?;
+            let rest = &json[start + quoted.len()..];
+            let open = rest
+                .find('[')
+                .ok_or_else(|| format!("{} is not a list", quoted))?;
+            let close = rest
+                .find(']')
+                .ok_or_else(|| format!("{} is not a list", quoted))?;
+
+            for item in rest[open + 1..close].split(',') {
+                let item = item.trim();
+                if item.is_empty() {
+                    continue;
+                }
+                let addr: usize = item
+                    .parse()
+                    .map_err(|_| format!("bad address {:?} in {}", item, quoted))?;
+                if addr >= MEMORY_SIZE {
+                    return Err(format!("address {} out of range in {}", addr, quoted));
+                }
+                coverage.mark(addr, flag);
+            }
+        }
+
+        Ok(coverage)
+    }
+
+    /// Produce a disassembly listing of `memory[start..end]`, two bytes per
+    /// line, annotated with the accesses recorded for each byte.
+    ///
+    /// Flags are shown as `X` (executed), `R` (read) and `W` (written), with
+    /// `-` for an access that didn't happen. Lines that were never executed
+    /// are shown as data.
+    pub fn listing(&self, memory: &[u8], start: usize, end: usize) -> String {
+        let mut out = String::new();
+        let end = end.min(memory.len());
+
+        let mut addr = start;
+        while addr < end {
+            let opcode = opcode_at(memory, addr).unwrap_or((memory[addr] as u16) << 8);
+            let text = if self.is_executed(addr) {
+                disassemble(opcode)
+            } else {
+                format!("DW 0x{:04X}", opcode)
+            };
+
+            out.push_str(&format!(
+                "0x{:03X}  {:04X}  {} {}  {}\n",
+                addr,
+                opcode,
+                flag_string(self.flags(addr)),
+                flag_string(self.flags(addr + 1)),
+                text
+            ));
+            addr += 2;
+        }
+
+        out
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+/// Three character summary of the flags for one byte, e.g. `X--`.
+fn flag_string(flags: u8) -> String {
+    let mut s = String::with_capacity(3);
+    s.push(if flags & EXECUTED != 0 { 'X' } else { '-' });
+    s.push(if flags & READ != 0 { 'R' } else { '-' });
+    s.push(if flags & WRITTEN != 0 { 'W' } else { '-' });
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let mut a = Coverage::new();
+        let mut b = Coverage::new();
+
+        a.mark(0x200, EXECUTED);
+        b.mark(0x200, READ);
+        b.mark(0x300, WRITTEN);
+        a.merge(&b);
+
+        assert_eq!(a.flags(0x200), EXECUTED | READ);
+        assert!(a.is_written(0x300));
+        assert_eq!(a.count(EXECUTED | READ | WRITTEN), 2);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut c = Coverage::new();
+
+        c.mark(0x200, EXECUTED);
+        c.mark(0x201, EXECUTED);
+        c.mark(0x2F0, READ | WRITTEN);
+
+        let parsed = Coverage::from_json(&c.to_json()).unwrap();
+
+        assert_eq!(parsed.flags(0x200), EXECUTED);
+        assert_eq!(parsed.flags(0x201), EXECUTED);
+        assert_eq!(parsed.flags(0x2F0), READ | WRITTEN);
+        assert_eq!(parsed.count(EXECUTED | READ | WRITTEN), 3);
+    }
+
+    #[test]
+    fn from_json_rejects_garbage() {
+        assert!(Coverage::from_json("{}").is_err());
+        assert!(Coverage::from_json("{\"executed\": [x], \"read\": [], \"written\": []}").is_err());
+    }
+
+    #[test]
+    fn listing() {
+        let mut memory = [0; MEMORY_SIZE];
+        memory[0x200] = 0x6A;
+        memory[0x201] = 0x02;
+        memory[0x202] = 0xF0;
+        memory[0x203] = 0x90;
+
+        let mut c = Coverage::new();
+        c.mark(0x200, EXECUTED);
+        c.mark(0x201, EXECUTED);
+        c.mark(0x202, READ);
+
+        let listing = c.listing(&memory, 0x200, 0x204);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "0x200  6A02  X-- X--  LD VA, 0x02");
+        assert_eq!(lines[1], "0x202  F090  -R- ---  DW 0xF090");
+    }
+}
@@ -0,0 +1,59 @@
+//! Machine faults: things a ROM can do that have no sensible result.
+//!
+//! When a fault happens the CPU stops where it is, leaving the PC on the
+//! offending instruction, so the state can be inspected.
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// `2nnn` with every stack slot already in use.
+    StackOverflow { pc: usize },
+    /// `00EE` with nothing on the stack to return to.
+    StackUnderflow { pc: usize },
+    /// An instruction fetch or I-relative access past the end of memory.
+    MemoryOutOfBounds { pc: usize, addr: usize },
+    /// An opcode that isn't part of the instruction set.
+    UnknownOpcode { pc: usize, opcode: u16 },
+    /// `0nnn`, a call to a machine code routine at `addr`, with nothing to
+    /// stand in for it.
+    MachineCode { pc: usize, addr: usize },
+}
+
+impl Fault {
+    /// Address of the instruction that faulted.
+    pub fn pc(&self) -> usize {
+        match *self {
+            Fault::StackOverflow { pc }
+            | Fault::StackUnderflow { pc }
+            | Fault::MemoryOutOfBounds { pc, .. }
+            | Fault::UnknownOpcode { pc, .. }
+            | Fault::MachineCode { pc, .. } => pc,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::StackOverflow { pc } => write!(f, "stack overflow at 0x{:03X}", pc),
+            Fault::StackUnderflow { pc } => write!(f, "stack underflow at 0x{:03X}", pc),
+            Fault::MemoryOutOfBounds { pc, addr } => write!(
+                f,
+                "memory access out of bounds at 0x{:03X}: 0x{:X}",
+                pc, addr
+            ),
+            Fault::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            Fault::MachineCode { pc, addr } => write!(
+                f,
+                "call to machine code at 0x{:03X} from 0x{:03X}, which can't be run",
+                addr, pc
+            ),
+        }
+    }
+}
+
+impl Error for Fault {}
@@ -0,0 +1,241 @@
+//! Display filters that hide flicker.
+//!
+//! CHIP-8 games move sprites by drawing over them with XOR to erase them and
+//! drawing them again, so a sprite is often missing from the display for
+//! part of a frame. These filters work from the display at the end of each
+//! emulated frame, plus what came before, and give each pixel a brightness
+//! from 0 to 1 that's drawn somewhere between the palette's background and
+//! foreground, or on CHIP-8X the colour board's. They're plain arithmetic on
+//! the CPU, so no GPU is needed.
+
+use std::fmt;
+use std::str::FromStr;
+
+use palette::{self, Palette};
+use platform::Platform;
+use {CPU, DISPLAY_HEIGHT, DISPLAY_WIDTH, MAX_DISPLAY_HEIGHT};
+
+/// Default `Filter::Blend` decay.
+pub const DECAY: f32 = 0.6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Show the display as it is.
+    None,
+    /// Pixels light up at once and fade out, keeping `decay` of their
+    /// brightness each frame.
+    Blend { decay: f32 },
+    /// Show a pixel if it was on in this frame or the one before.
+    Deflicker,
+    /// Rough model of the COSMAC VIP's CRT: pixels take a frame to fully
+    /// light up, and leave a short ghost behind when they go off.
+    Vip,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let error = || {
+            format!(
+                "unknown filter {:?} (none, blend[:decay], deflicker, vip)",
+                s
+            )
+        };
+        match s {
+            "none" => Ok(Filter::None),
+            "blend" => Ok(Filter::Blend { decay: DECAY }),
+            "deflicker" => Ok(Filter::Deflicker),
+            "vip" => Ok(Filter::Vip),
+            _ => {
+                let decay = s
+                    .strip_prefix("blend:")
+                    .and_then(|decay| decay.parse().ok())
+                    .ok_or_else(error)?;
+                if (0.0..1.0).contains(&decay) {
+                    Ok(Filter::Blend { decay })
+                } else {
+                    Err(format!("blend decay must be from 0 to 1, not {}", decay))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::None => write!(f, "none"),
+            Filter::Blend { decay } => write!(f, "blend:{}", decay),
+            Filter::Deflicker => write!(f, "deflicker"),
+            Filter::Vip => write!(f, "vip"),
+        }
+    }
+}
+
+/// A filter and the display history it needs.
+pub struct Phosphor {
+    pub filter: Filter,
+    /// Brightness of each pixel.
+    levels: Vec<f32>,
+    /// Which pixels were on at the end of the last frame.
+    last: Vec<bool>,
+    /// Rows in the display, as of the last frame.
+    height: usize,
+}
+
+impl Phosphor {
+    pub fn new(filter: Filter) -> Phosphor {
+        Phosphor {
+            filter,
+            levels: vec![0.0; DISPLAY_WIDTH * MAX_DISPLAY_HEIGHT],
+            last: vec![false; DISPLAY_WIDTH * MAX_DISPLAY_HEIGHT],
+            height: DISPLAY_HEIGHT,
+        }
+    }
+
+    /// Take in the display at the end of an emulated frame.
+    pub fn update(&mut self, cpu: &CPU) {
+        self.height = cpu.display_height();
+        for pixel in 0..cpu.display_size() {
+            let on = cpu.get_pixel(pixel) == 1;
+            let level = &mut self.levels[pixel];
+            *level = match self.filter {
+                Filter::None => on as u8 as f32,
+                Filter::Blend { decay } => {
+                    if on {
+                        1.0
+                    } else {
+                        *level * decay
+                    }
+                }
+                Filter::Deflicker => (on || self.last[pixel]) as u8 as f32,
+                Filter::Vip => {
+                    if on {
+                        *level + (1.0 - *level) * 0.75
+                    } else {
+                        *level * 0.3
+                    }
+                }
+            };
+            self.last[pixel] = on;
+        }
+    }
+
+    /// Brightness of each pixel, from 0 to 1.
+    pub fn levels(&self) -> &[f32] {
+        &self.levels[..DISPLAY_WIDTH * self.height]
+    }
+
+    /// The filtered display as RGB24, like `Palette::render`. `cpu` gives
+    /// the colours on CHIP-8X.
+    pub fn render(&self, cpu: &CPU, palette: &Palette, scale: usize) -> Vec<u8> {
+        let width = DISPLAY_WIDTH * scale;
+        let mut rgb = Vec::with_capacity(DISPLAY_WIDTH * self.height * scale * scale * 3);
+
+        for y in 0..self.height * scale {
+            for x in 0..width {
+                let pixel = (y / scale) * DISPLAY_WIDTH + x / scale;
+                let level = self.levels[pixel];
+                rgb.extend_from_slice(&match cpu.platform {
+                    Platform::Chip8X => {
+                        let board = &cpu.color_board;
+                        palette::mix(board.color(pixel, false), board.color(pixel, true), level)
+                    }
+                    _ => palette.mix(level),
+                });
+            }
+        }
+        rgb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DISPLAY_SIZE;
+
+    /// Levels of pixel 0 over frames where it's on or off.
+    fn run(filter: Filter, frames: &[bool]) -> Vec<f32> {
+        let mut phosphor = Phosphor::new(filter);
+        let mut cpu = CPU::new();
+        frames
+            .iter()
+            .map(|&on| {
+                cpu.set_pixel(0, on as u8);
+                phosphor.update(&cpu);
+                phosphor.levels()[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn none() {
+        assert_eq!(run(Filter::None, &[true, false, true]), vec![1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn blend() {
+        let levels = run(Filter::Blend { decay: 0.5 }, &[true, false, false, true]);
+        assert_eq!(levels, vec![1.0, 0.5, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn deflicker() {
+        let levels = run(Filter::Deflicker, &[false, true, false, false, true, true]);
+        assert_eq!(levels, vec![0.0, 1.0, 1.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn vip_ghosting() {
+        let levels = run(Filter::Vip, &[true, true, false, false]);
+        assert_eq!(levels[0], 0.75);
+        assert!(levels[1] > levels[0] && levels[1] < 1.0);
+        assert!(levels[2] > 0.0 && levels[3] < levels[2]);
+    }
+
+    #[test]
+    fn render() {
+        let mut cpu = CPU::new();
+        cpu.set_pixel(1, 1);
+        let mut phosphor = Phosphor::new(Filter::Blend { decay: 0.5 });
+        phosphor.update(&cpu);
+        cpu.set_pixel(1, 0);
+        phosphor.update(&cpu);
+
+        let rgb = phosphor.render(&cpu, &Palette::default(), 1);
+        assert_eq!(rgb.len(), DISPLAY_SIZE * 3);
+        assert_eq!(&rgb[..6], &[0, 0, 0, 0x80, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn render_color_board() {
+        let mut cpu = CPU::new();
+        cpu.set_platform(Platform::Chip8X);
+        cpu.set_pixel(1, 1);
+        let mut phosphor = Phosphor::new(Filter::Blend { decay: 0.5 });
+        phosphor.update(&cpu);
+        cpu.set_pixel(1, 0);
+        phosphor.update(&cpu);
+
+        // Half way from the board's blue background to its red foreground
+        let board = &cpu.color_board;
+        let rgb = phosphor.render(&cpu, &Palette::default(), 1);
+        assert_eq!(&rgb[..3], &board.color(0, false));
+        assert_eq!(
+            &rgb[3..6],
+            &palette::mix(board.color(1, false), board.color(1, true), 0.5)
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!("blend".parse(), Ok(Filter::Blend { decay: DECAY }));
+        assert_eq!("blend:0.25".parse(), Ok(Filter::Blend { decay: 0.25 }));
+        for filter in &[Filter::None, Filter::Deflicker, Filter::Vip] {
+            assert_eq!(filter.to_string().parse(), Ok(*filter));
+        }
+        assert!("blend:2".parse::<Filter>().is_err());
+        assert!("blur".parse::<Filter>().is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Fuzzing entry points.
+//!
+//! These take an arbitrary byte string, load it as a ROM and run it for a
+//! bounded number of cycles. Whatever the bytes are, the core must not panic:
+//! a program that does something impossible ends in a `Fault`. They're meant
+//! to be called from a fuzzer's target, e.g. with cargo-fuzz:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| { chip8::fuzz::run(data); });
+//! ```
+//!
+//! The first byte of the input picks the quirks, the rest is the ROM.
+
+use backend::{self, Backend, Interpreter};
+use lockstep::{Lockstep, Machine};
+use quirks::{MemoryPolicy, StackPolicy, SysPolicy};
+use CPU;
+
+/// How many instructions to run each input for.
+pub const CYCLES: usize = 10_000;
+
+/// A CPU with `data` loaded: quirks from the first byte, ROM from the rest.
+pub fn cpu_for(data: &[u8]) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.seed_rng(0);
+
+    let (config, rom) = match data.split_first() {
+        Some((&config, rom)) => (config, rom),
+        None => (0, data),
+    };
+    if config & 0x01 != 0 {
+        cpu.quirks.stack = StackPolicy::Wrap;
+    }
+    if config & 0x02 != 0 {
+        cpu.quirks.memory = MemoryPolicy::Fault;
+    }
+    if config & 0x04 != 0 {
+        cpu.quirks.display_wait = true;
+    }
+    if config & 0x08 != 0 {
+        cpu.quirks.sys = SysPolicy::Ignore;
+    }
+
+    cpu.load_program(rom);
+    cpu
+}
+
+/// Run an input on the interpreter until it faults or `CYCLES` have passed,
+/// answering key waits with key 0 and starting a new frame whenever it
+/// waits for one. Returns the final state.
+pub fn run(data: &[u8]) -> CPU {
+    let mut cpu = cpu_for(data);
+    let mut executed = 0;
+
+    while executed < CYCLES && cpu.fault.is_none() {
+        executed += Interpreter.run(&mut cpu, CYCLES - executed);
+        if cpu.waitkey {
+            cpu.finish_waitkey(0);
+            executed += 1;
+        }
+        if cpu.vblank_wait {
+            cpu.vblank();
+            executed += 1;
+        }
+    }
+    cpu
+}
+
+/// Run an input on every backend in lockstep with the interpreter, for
+/// `cycles` instructions. Panics if any of them disagree.
+pub fn differential(data: &[u8], cycles: usize) {
+    for &name in backend::NAMES.iter().filter(|&&name| name != "interpreter") {
+        let mut lockstep = Lockstep::new(
+            Machine::new(cpu_for(data), Box::new(Interpreter)),
+            Machine::new(cpu_for(data), backend::by_name(name).unwrap()),
+        );
+
+        while lockstep.steps < cycles && lockstep.a.cpu.fault.is_none() {
+            let remaining = cycles - lockstep.steps;
+            if let Err(divergence) = lockstep.run(remaining) {
+                panic!("{} diverged from the interpreter: {}", name, divergence);
+            }
+            if lockstep.is_stopped() {
+                if lockstep.a.cpu.waitkey {
+                    lockstep.finish_waitkey(0);
+                }
+                lockstep.vblank();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fault::Fault;
+    use rand::prelude::{Rng, SeedableRng, SmallRng};
+
+    /// Random ROMs, biased towards valid opcodes so they get past the first
+    /// few instructions.
+    fn random_inputs(count: usize) -> Vec<Vec<u8>> {
+        let mut rng = SmallRng::from_seed([0x5E; 16]);
+        (0..count)
+            .map(|_| {
+                let len = rng.gen_range(1, 256);
+                let mut data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                // Keep most jumps and calls inside the ROM
+                for i in (1..data.len()).step_by(2) {
+                    if data[i] >> 4 == 0x1 || data[i] >> 4 == 0x2 {
+                        data[i] = (data[i] & 0xF0) | 0x02;
+                    }
+                }
+                data
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_roms_dont_panic() {
+        for data in random_inputs(500) {
+            run(&data);
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_random_roms() {
+        for data in random_inputs(20) {
+            differential(&data, 500);
+        }
+    }
+
+    #[test]
+    fn unknown_opcode_faults() {
+        let cpu = run(&[0, 0x00, 0xE0, 0xE1, 0xFF]);
+        assert_eq!(
+            cpu.fault,
+            Some(Fault::UnknownOpcode {
+                pc: 0x202,
+                opcode: 0xE1FF
+            })
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let data = [
+            0x00,
+            0x60, 0xFF, // 200: LD V0, 0xFF
+            0xF0, 0x29, // 202: LD F, V0
+            0xE0, 0x9E, // 204: SKP V0
+            0xE0, 0xA1, // 206: SKNP V0
+            0xE1, 0xFF, // 208: DW 0xE1FF (skipped)
+            0xD0, 0x05, // 20A: DRW V0, V0, 5
+            0xB0, 0xFF, // 20C: JP V0, 0xFF
+        ];
+        // Runs into the zeroes before the ROM
+        let cpu = run(&data);
+        assert_eq!(cpu.fault.map(|fault| fault.pc()), Some(0x1FE));
+    }
+}
@@ -0,0 +1,193 @@
+//! Golden-image tests: compare the display against a checked-in reference.
+//!
+//! Golden images are text files in the format written by
+//! `headless::screen_text`, so they can be read and reviewed in a diff. Set
+//! `CHIP8_UPDATE_GOLDEN=1` to rewrite them from the current output instead
+//! of comparing, after checking the change is intended.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use backend::Interpreter;
+use headless::{self, Headless, InputScript};
+use CPU;
+
+/// Environment variable that switches to update mode.
+pub const UPDATE_VAR: &str = "CHIP8_UPDATE_GOLDEN";
+
+/// Whether golden images should be rewritten rather than checked.
+pub fn update_mode() -> bool {
+    env::var_os(UPDATE_VAR).is_some_and(|value| value != "0")
+}
+
+/// Count the pixels that differ between two screens in `screen_text`
+/// format.
+pub fn count_differences(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.lines().flat_map(str::chars).collect();
+    let b: Vec<char> = b.lines().flat_map(str::chars).collect();
+    let mismatched = a.iter().zip(b.iter()).filter(|(a, b)| a != b).count();
+    mismatched + a.len().max(b.len()) - a.len().min(b.len())
+}
+
+/// Overlay two screens: `#` and `.` where they agree, `+` for a pixel
+/// that's only on in `actual`, `-` for one that's only on in `expected` and
+/// `?` where only one of them has a pixel at all. Rows with a difference
+/// are marked with `>`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+
+    for row in 0..expected.len().max(actual.len()) {
+        let mut e = expected.get(row).map_or("", |line| line).chars();
+        let mut a = actual.get(row).map_or("", |line| line).chars();
+        let mut line = String::new();
+        let mut differs = false;
+        loop {
+            let c = match (e.next(), a.next()) {
+                (None, None) => break,
+                (e, a) if e == a => e.unwrap(),
+                (Some('#'), _) => '-',
+                (_, Some('#')) => '+',
+                // One screen is bigger than the other
+                _ => '?',
+            };
+            differs |= c == '+' || c == '-' || c == '?';
+            line.push(c);
+        }
+        out.push_str(if differs { "> " } else { "  " });
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// A display that didn't match its golden image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Mismatch {
+    pub fn pixels(&self) -> usize {
+        count_differences(&self.expected, &self.actual)
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} doesn't match ({} pixels differ; + only in actual, - only in expected):",
+            self.path.display(),
+            self.pixels()
+        )?;
+        write!(f, "{}", diff(&self.expected, &self.actual))?;
+        write!(f, "rerun with {}=1 to update it", UPDATE_VAR)
+    }
+}
+
+/// Compare a screen in `screen_text` format against the golden image at
+/// `path`, or write it there in update mode. A missing golden image is an
+/// error, not an empty screen.
+pub fn check_text(actual: &str, path: &Path) -> Result<(), String> {
+    if update_mode() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        return fs::write(path, actual).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    let expected = fs::read_to_string(path).map_err(|e| {
+        format!(
+            "{}: {} (run with {}=1 to create it)",
+            path.display(),
+            e,
+            UPDATE_VAR
+        )
+    })?;
+    if count_differences(&expected, actual) == 0 {
+        Ok(())
+    } else {
+        Err(Mismatch {
+            path: path.to_path_buf(),
+            expected,
+            actual: actual.to_string(),
+        }
+        .to_string())
+    }
+}
+
+/// Compare a CPU's display against the golden image at `path`.
+pub fn check(cpu: &CPU, path: &Path) -> Result<(), String> {
+    check_text(&headless::screen_text(cpu), path)
+}
+
+/// Like `check`, but panics with the diff. For use in tests.
+pub fn assert_golden(cpu: &CPU, path: &Path) {
+    if let Err(e) = check(cpu, path) {
+        panic!("{}", e);
+    }
+}
+
+/// Run a ROM on the interpreter for `frames` frames, replaying `input`, and
+/// return the CPU.
+pub fn run_rom(rom: &[u8], frames: usize, input: &InputScript) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.seed_rng(0);
+    cpu.load_program(rom);
+
+    let mut headless = Headless::new(cpu, Box::new(Interpreter));
+    headless.input = input.clone();
+    headless.run_frames(frames);
+    headless.cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn differences() {
+        assert_eq!(count_differences("#..\n.#.\n", "#..\n.#.\n"), 0);
+        assert_eq!(count_differences("#..\n.#.\n", "...\n.##\n"), 2);
+        assert_eq!(count_differences("#..\n", "#..\n...\n"), 3);
+    }
+
+    #[test]
+    fn readable_diff() {
+        assert_eq!(
+            diff("#..\n.#.\n...\n", "#..\n..#\n"),
+            "  #..\n> .-+\n> ???\n"
+        );
+    }
+
+    #[test]
+    fn mismatch_message() {
+        let mismatch = Mismatch {
+            path: PathBuf::from("golden/x.txt"),
+            expected: "#.\n".to_string(),
+            actual: "##\n".to_string(),
+        };
+        assert_eq!(
+            mismatch.to_string(),
+            "golden/x.txt doesn't match (1 pixels differ; + only in actual, - only in \
+             expected):\n> #+\nrerun with CHIP8_UPDATE_GOLDEN=1 to update it"
+        );
+    }
+
+    #[test]
+    fn keypad_rom() {
+        // Waits for a key and draws its digit
+        let rom = [0xF0, 0x0A, 0xF0, 0x29, 0x61, 0x08, 0xD1, 0x15, 0x12, 0x08];
+        let input = InputScript::new().press(2, 0xA).release(3, 0xA);
+        let cpu = run_rom(&rom, 5, &input);
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/keypad_a.txt");
+        assert_golden(&cpu, &path);
+    }
+}
@@ -0,0 +1,347 @@
+//! Running ROMs without a window, for tests and tools.
+
+use backend::Backend;
+use timing::{FrameClock, Timing};
+use {CPU, DISPLAY_WIDTH};
+
+/// Instructions executed per 60 Hz frame when running headless, unless
+/// `Headless::clock` says otherwise.
+pub const CYCLES_PER_FRAME: usize = 10;
+
+/// Key presses and releases to replay, by frame.
+///
+/// As text, one event per line: the frame number, `press` or `release`, and
+/// the key as a hex digit. Blank lines and anything after a `#` are ignored.
+///
+/// ```text
+/// 10 press A
+/// 12 release A
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+    /// (frame, key, pressed), in frame order.
+    events: Vec<(usize, u8, bool)>,
+}
+
+impl InputScript {
+    pub fn new() -> InputScript {
+        InputScript { events: Vec::new() }
+    }
+
+    /// Press `key` at the start of `frame`.
+    pub fn press(mut self, frame: usize, key: u8) -> InputScript {
+        self.add(frame, key, true);
+        self
+    }
+
+    /// Release `key` at the start of `frame`.
+    pub fn release(mut self, frame: usize, key: u8) -> InputScript {
+        self.add(frame, key, false);
+        self
+    }
+
+    fn add(&mut self, frame: usize, key: u8, pressed: bool) {
+        let index = self
+            .events
+            .iter()
+            .position(|&(f, _, _)| f > frame)
+            .unwrap_or(self.events.len());
+        self.events.insert(index, (frame, key & 0xF, pressed));
+    }
+
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            if fields.len() != 3 {
+                return Err(error(
+                    "expected a frame, press or release, and a key".to_string(),
+                ));
+            }
+
+            let frame = fields[0]
+                .parse()
+                .map_err(|_| error(format!("bad frame {:?}", fields[0])))?;
+            let pressed = match fields[1] {
+                "press" => true,
+                "release" => false,
+                event => return Err(error(format!("unknown event {:?}", event))),
+            };
+            let key = u8::from_str_radix(fields[2], 16)
+                .ok()
+                .filter(|&key| key < 16)
+                .ok_or_else(|| error(format!("bad key {:?}", fields[2])))?;
+            script.add(frame, key, pressed);
+        }
+
+        Ok(script)
+    }
+
+    /// Events at the start of `frame`, as (key, pressed).
+    pub fn events_at(&self, frame: usize) -> Vec<(u8, bool)> {
+        self.events
+            .iter()
+            .filter(|&&(f, _, _)| f == frame)
+            .map(|&(_, key, pressed)| (key, pressed))
+            .collect()
+    }
+
+    /// Whether there's a key press in `frame` or later.
+    pub fn presses_after(&self, frame: usize) -> bool {
+        self.events
+            .iter()
+            .any(|&(f, _, pressed)| f >= frame && pressed)
+    }
+}
+
+/// A CPU run frame by frame, with nobody at the keyboard.
+pub struct Headless {
+    pub cpu: CPU,
+    backend: Box<dyn Backend>,
+    /// Key to answer `Fx0A` with. Without one, the CPU waits for the next
+    /// press in `input`, and the run ends if there isn't one.
+    pub key: Option<u8>,
+    pub input: InputScript,
+    /// Frames run so far.
+    pub frames: usize,
+    /// How many instructions fit in a frame.
+    pub clock: FrameClock,
+}
+
+impl Headless {
+    pub fn new(cpu: CPU, backend: Box<dyn Backend>) -> Headless {
+        Headless {
+            cpu,
+            backend,
+            key: None,
+            input: InputScript::new(),
+            frames: 0,
+            clock: FrameClock::new(Timing::default()),
+        }
+    }
+
+    /// Run one frame's worth of instructions. Returns false if the CPU
+    /// can't go on, because it faulted or is waiting for a key that will
+    /// never come.
+    pub fn run_frame(&mut self) -> bool {
+        self.cpu.vblank();
+        for (key, pressed) in self.input.events_at(self.frames) {
+            self.cpu.keypad[key as usize] = pressed as u8;
+            if pressed && self.cpu.waitkey {
+                self.cpu.finish_waitkey(key);
+            }
+        }
+
+        self.clock.start_frame();
+        loop {
+            let batch = self.clock.batch(&self.cpu);
+            if batch == 0 {
+                break;
+            }
+            let executed = self.backend.run(&mut self.cpu, batch);
+            self.clock.ran(executed);
+            if self.cpu.fault.is_some() {
+                return false;
+            }
+            if self.cpu.vblank_wait {
+                self.clock.idle();
+                break;
+            }
+            if self.cpu.waitkey {
+                match self.key {
+                    Some(key) => self.cpu.finish_waitkey(key),
+                    // Sit out the rest of the frame
+                    None if self.input.presses_after(self.frames + 1) => {
+                        self.clock.idle();
+                        break;
+                    }
+                    None => return false,
+                }
+            }
+        }
+        self.frames += 1;
+        true
+    }
+
+    /// Run up to `frames` frames, stopping early if the CPU can't go on.
+    /// Returns how many were run.
+    pub fn run_frames(&mut self, frames: usize) -> usize {
+        let start = self.frames;
+        while self.frames - start < frames && self.run_frame() {}
+        self.frames - start
+    }
+}
+
+/// The display as text: one line per row, `#` for a pixel that's on and
+/// `.` for one that's off.
+pub fn screen_text(cpu: &CPU) -> String {
+    let height = cpu.display_height();
+    let mut out = String::with_capacity((DISPLAY_WIDTH + 1) * height);
+    for y in 0..height {
+        for x in 0..DISPLAY_WIDTH {
+            out.push(if cpu.get_pixel(y * DISPLAY_WIDTH + x) == 1 {
+                '#'
+            } else {
+                '.'
+            });
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::Interpreter;
+    use DISPLAY_HEIGHT;
+
+    #[test]
+    fn screen() {
+        let mut cpu = CPU::new();
+        cpu.set_pixel(1, 1);
+        cpu.set_pixel(DISPLAY_WIDTH * 2 - 1, 1);
+
+        let text = screen_text(&cpu);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), DISPLAY_HEIGHT);
+        assert!(lines[0].starts_with(".#.."));
+        assert!(lines[1].ends_with("..#"));
+        assert_eq!(text.matches('#').count(), 2);
+    }
+
+    #[test]
+    fn waitkey() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x200] = 0xF3; // LD V3, K
+        cpu.memory[0x201] = 0x0A;
+        cpu.memory[0x202] = 0x12; // JP 0x202
+        cpu.memory[0x203] = 0x02;
+
+        let mut headless = Headless::new(cpu, Box::new(Interpreter));
+        assert_eq!(headless.run_frames(5), 0);
+
+        headless.cpu.pc = 0x200;
+        headless.key = Some(0xC);
+        assert_eq!(headless.run_frames(5), 5);
+        assert_eq!(headless.cpu.v_reg[3], 0xC);
+    }
+
+    #[test]
+    fn display_wait() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x200] = 0xD0; // DRW V0, V0, 1
+        cpu.memory[0x201] = 0x01;
+        cpu.memory[0x202] = 0x71; // ADD V1, 1
+        cpu.memory[0x203] = 0x01;
+        cpu.memory[0x204] = 0x12; // JP 0x200
+        cpu.memory[0x205] = 0x00;
+        cpu.quirks.display_wait = true;
+        cpu.delay_timer = 10;
+
+        // One draw a frame, and nothing after it until the next, but the
+        // timers keep counting down through the wait
+        let mut headless = Headless::new(cpu, Box::new(Interpreter));
+        assert_eq!(headless.run_frames(3), 3);
+        assert_eq!(headless.cpu.v_reg[1], 2);
+        assert_eq!(headless.cpu.pc, 0x202);
+        assert!(headless.cpu.vblank_wait);
+        assert_eq!(headless.cpu.delay_timer, 7);
+
+        headless.cpu.quirks.display_wait = false;
+        headless.run_frames(1);
+        assert!(headless.cpu.v_reg[1] > 3);
+        assert_eq!(headless.cpu.delay_timer, 6);
+    }
+
+    #[test]
+    fn vip_timing() {
+        // Sprites are slower than register loads on the VIP
+        let mut cpu = CPU::new();
+        cpu.memory[0x200] = 0x71; // ADD V1, 1
+        cpu.memory[0x201] = 0x01;
+        cpu.memory[0x202] = 0x12; // JP 0x200
+        cpu.memory[0x203] = 0x00;
+        let mut adds = Headless::new(cpu.clone(), Box::new(Interpreter));
+        adds.clock = FrameClock::new(Timing::Vip);
+        adds.run_frames(1);
+
+        cpu.memory[0x200] = 0xD0; // DRW V0, V0, 15
+        cpu.memory[0x201] = 0x0F;
+        cpu.memory[0x202] = 0x71; // ADD V1, 1
+        cpu.memory[0x203] = 0x01;
+        cpu.memory[0x204] = 0x12; // JP 0x200
+        cpu.memory[0x205] = 0x00;
+        let mut sprites = Headless::new(cpu, Box::new(Interpreter));
+        sprites.clock = FrameClock::new(Timing::Vip);
+        sprites.run_frames(1);
+
+        assert!(adds.cpu.v_reg[1] > CYCLES_PER_FRAME as u8);
+        assert!(sprites.cpu.v_reg[1] < adds.cpu.v_reg[1] / 2);
+    }
+
+    #[test]
+    fn timers_tick_once_a_frame() {
+        // LD V0, 60; LD DT, V0; then poll DT until it's 0 and stop
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rom = [
+            0x60, 0x3C, 0xF0, 0x15,
+            0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, // 204: LD V1, DT; SE V1, 0; JP 0x204
+            0x12, 0x0A,                         // 20A: JP 0x20A
+        ];
+        for &timing in &[Timing::default(), Timing::Vip] {
+            let mut cpu = CPU::new();
+            cpu.load_program(&rom);
+            let mut headless = Headless::new(cpu, Box::new(Interpreter));
+            headless.clock = FrameClock::new(timing);
+
+            // DT is set in the first frame and runs out 60 frames later
+            assert_eq!(headless.run_frames(60), 60);
+            assert_eq!(headless.cpu.delay_timer, 1, "{}", timing);
+            assert_ne!(headless.cpu.pc, 0x20A, "{}", timing);
+            headless.run_frames(1);
+            assert_eq!(headless.cpu.pc, 0x20A, "{}", timing);
+        }
+    }
+
+    #[test]
+    fn input_script() {
+        let script =
+            InputScript::parse("# wait, then press 7\n3 press 7\n\n5 release 7\n").unwrap();
+        assert_eq!(script, InputScript::new().release(5, 7).press(3, 7));
+        assert_eq!(script.events_at(3), vec![(7, true)]);
+        assert!(script.presses_after(3));
+        assert!(!script.presses_after(4));
+
+        assert!(InputScript::parse("3 press").is_err());
+        assert!(InputScript::parse("3 hold 7").is_err());
+        assert!(InputScript::parse("3 press 10").is_err());
+    }
+
+    #[test]
+    fn scripted_waitkey() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x200] = 0xF3; // LD V3, K
+        cpu.memory[0x201] = 0x0A;
+        cpu.memory[0x202] = 0x12; // JP 0x202
+        cpu.memory[0x203] = 0x02;
+
+        let mut headless = Headless::new(cpu, Box::new(Interpreter));
+        headless.input = InputScript::new().press(3, 0x9).release(4, 0x9);
+
+        assert_eq!(headless.run_frames(3), 3);
+        assert!(headless.cpu.waitkey);
+        assert_eq!(headless.run_frames(1), 1);
+        assert!(!headless.cpu.waitkey);
+        assert_eq!(headless.cpu.v_reg[3], 0x9);
+        assert_eq!(headless.cpu.keypad[0x9], 1);
+        assert_eq!(headless.run_frames(1), 1);
+        assert_eq!(headless.cpu.keypad[0x9], 0);
+    }
+}
@@ -1,14 +1,52 @@
+extern crate gif;
+#[cfg(feature = "jit")]
+extern crate libc;
+extern crate png;
 extern crate rand;
 extern crate sdl2;
 
-use rand::prelude::{thread_rng, Rng};
+use rand::prelude::{FromEntropy, Rng, SeedableRng, SmallRng};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 
 use sdl2::keyboard::Keycode;
 
-/// Starting address for program ROMs.
-const PROGRAM_ROM_START: usize = 0x200;
+pub mod analysis;
+pub mod audio;
+pub mod backend;
+pub mod cdp1802;
+pub mod coverage;
+pub mod disasm;
+pub mod fault;
+pub mod filter;
+pub mod fuzz;
+pub mod golden;
+pub mod headless;
+pub mod lockstep;
+pub mod palette;
+pub mod platform;
+pub mod quirks;
+pub mod record;
+pub mod scale;
+pub mod screenshot;
+pub mod smc;
+pub mod suite;
+pub mod timing;
+pub mod vip;
+
+use coverage::Coverage;
+use fault::Fault;
+use platform::{ColorBoard, Platform};
+use quirks::{EdgePolicy, MemoryPolicy, Quirks, StackPolicy, SysPolicy};
+use smc::SmcDetector;
+
+/// Size of the addressable memory.
+pub const MEMORY_SIZE: usize = 4096;
+/// Starting address for program ROMs, unless the platform says otherwise.
+pub const PROGRAM_ROM_START: usize = 0x200;
+/// Number of return addresses the stack can hold.
+pub const STACK_SIZE: usize = 16;
 /// Starting address for the fontset.
 const FONTSET_START: usize = 0x000;
 
@@ -16,11 +54,13 @@ const FONTSET_START: usize = 0x000;
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 pub const DISPLAY_SIZE: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
+/// Height of the tallest display, on hi-res CHIP-8.
+pub const MAX_DISPLAY_HEIGHT: usize = 64;
 
 /// The size of the display buffer in memory (RGB24 pixel format). 3 times as
-/// big as the emulated display because each pixel has to be represented by an
-/// RGB triplet.
-pub const DISPLAY_BUFFER_SIZE: usize = DISPLAY_SIZE * 3;
+/// big as the biggest emulated display because each pixel has to be
+/// represented by an RGB triplet.
+pub const DISPLAY_BUFFER_SIZE: usize = DISPLAY_WIDTH * MAX_DISPLAY_HEIGHT * 3;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 const CHIP8_FONTSET: [u8; 80] = [
@@ -69,37 +109,76 @@ impl Opcode for u16 {
     }
 }
 
+/// Host code standing in for a machine code routine called with `0nnn`.
+/// It's called with the PC already on the next instruction.
+pub type SysRoutine = fn(&mut CPU);
+
 /// Main CHIP-8 CPU data structure.
+#[derive(Clone)]
 pub struct CPU {
     pub opcode: u16, // current opcode
-    pub memory: [u8; 4096],
+    pub memory: [u8; MEMORY_SIZE],
     pub v_reg: [u8; 16], // registers
     pub i_addr: usize,   // u16, address register
     pub pc: usize,       // u16, program counter
     pub display: [u8; DISPLAY_BUFFER_SIZE],
-    pub stack: [usize; 16], // u16
+    pub stack: [usize; STACK_SIZE], // u16
     pub sp: usize,          // u8, stack pointer
     pub delay_timer: u8,
     pub sound_timer: u8,
     pub keypad: [u8; 16],
+    /// The CHIP-8X second keypad.
+    pub keypad2: [u8; 16],
     pub waitkey: bool,
+    /// Set by `Dxyn` under the `display_wait` quirk: the CPU is waiting for
+    /// the next frame to start. See `vblank`.
+    pub vblank_wait: bool,
+    /// Set while CHIP-8E's `Fx4F` waits for the delay timer to run out.
+    pub delay_wait: bool,
+    /// Memory accesses made so far, if coverage tracking is enabled.
+    pub coverage: Option<Coverage>,
+    /// Self-modifying code detection, if enabled.
+    pub smc: Option<SmcDetector>,
+    pub quirks: Quirks,
+    /// Which variant of CHIP-8 to run. See `set_platform`.
+    pub platform: Platform,
+    /// Colours of the CHIP-8X display.
+    pub color_board: ColorBoard,
+    /// Set when the CPU hits a fault, after which it stops executing.
+    pub fault: Option<Fault>,
+    /// Routines to run for `0nnn`, by address. See `register_sys`.
+    pub sys_routines: BTreeMap<usize, SysRoutine>,
+    /// Random number generator for `Cxkk`. Seed it with `seed_rng` to make
+    /// runs reproducible.
+    pub rng: SmallRng,
 }
 
 impl CPU {
     pub fn new() -> CPU {
         let mut cpu = CPU {
             opcode: 0,
-            memory: [0; 4096],
+            memory: [0; MEMORY_SIZE],
             v_reg: [0; 16],
             i_addr: 0,
             pc: PROGRAM_ROM_START,
             display: [0; DISPLAY_BUFFER_SIZE],
-            stack: [0; 16],
+            stack: [0; STACK_SIZE],
             sp: 0,
             delay_timer: 0,
             sound_timer: 0,
             keypad: [0; 16],
+            keypad2: [0; 16],
             waitkey: false,
+            vblank_wait: false,
+            delay_wait: false,
+            coverage: None,
+            smc: None,
+            quirks: Quirks::default(),
+            platform: Platform::default(),
+            color_board: ColorBoard::new(),
+            fault: None,
+            sys_routines: BTreeMap::new(),
+            rng: SmallRng::from_entropy(),
         };
         // You shouldn't have to load the fontset in separately, assume it's
         // loaded in when the machine starts.
@@ -114,15 +193,125 @@ impl CPU {
         }
     }
 
+    /// Switch to another variant of CHIP-8, moving the PC to where its
+    /// programs start. Do this before loading the program.
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.pc = platform.program_start();
+    }
+
+    /// Rows of pixels in the display on this platform. It's always
+    /// `DISPLAY_WIDTH` wide.
+    pub fn display_height(&self) -> usize {
+        self.platform.display_height()
+    }
+
+    /// Number of pixels in the display on this platform.
+    pub fn display_size(&self) -> usize {
+        DISPLAY_WIDTH * self.display_height()
+    }
+
     /// Load a program ROM into memory.
     pub fn load_rom(&mut self, filename: &str) {
         let mut file = File::open(filename).unwrap();
 
         // Reads up to memory (4 KB) bytes
-        file.read(&mut self.memory[(PROGRAM_ROM_START as usize)..])
+        file.read(&mut self.memory[self.platform.program_start()..])
             .unwrap();
     }
 
+    /// Reseed the random number generator, so `Cxkk` produces the same
+    /// sequence every run.
+    pub fn seed_rng(&mut self, seed: u64) {
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (seed >> (8 * (i % 8))) as u8;
+        }
+        self.rng = SmallRng::from_seed(bytes);
+    }
+
+    /// Load a program ROM from bytes, e.g. one built by a test.
+    /// Anything that doesn't fit is cut off.
+    pub fn load_program(&mut self, rom: &[u8]) {
+        let start = self.platform.program_start();
+        let len = rom.len().min(MEMORY_SIZE - start);
+        self.memory[start..start + len].copy_from_slice(&rom[..len]);
+    }
+
+    /// Start recording which bytes of memory are executed, read and written.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Coverage::new());
+        }
+    }
+
+    /// Run `routine` whenever the ROM calls machine code at `addr` with
+    /// `0nnn`, for ROMs that need a known routine to work. The caching
+    /// backends don't see memory written by routines, so they shouldn't
+    /// write over code.
+    pub fn register_sys(&mut self, addr: usize, routine: SysRoutine) {
+        self.sys_routines.insert(addr, routine);
+    }
+
+    /// Start reporting writes to executed code and execution of written
+    /// bytes.
+    pub fn enable_smc_detection(&mut self) {
+        if self.smc.is_none() {
+            self.smc = Some(SmcDetector::new());
+        }
+    }
+
+    /// Record a memory access in the coverage map and self-modifying code
+    /// detector, if we're keeping them.
+    fn record_access(&mut self, addr: usize, flag: u8) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.mark(addr, flag);
+        }
+        if let Some(ref mut smc) = self.smc {
+            smc.record(self.pc, addr, flag);
+        }
+    }
+
+    /// Check that `len` bytes starting at `addr` lie within memory, applying
+    /// the memory policy if they don't. Returns false if the access faulted.
+    fn check_access(&mut self, addr: usize, len: usize) -> bool {
+        if addr + len <= MEMORY_SIZE {
+            return true;
+        }
+        match self.quirks.memory {
+            MemoryPolicy::Wrap => true,
+            MemoryPolicy::Warn => {
+                eprintln!(
+                    "warning: access to 0x{:X} at 0x{:03X} wrapped around memory",
+                    addr + len - 1,
+                    self.pc
+                );
+                true
+            }
+            MemoryPolicy::Fault => {
+                self.fault = Some(Fault::MemoryOutOfBounds {
+                    pc: self.pc,
+                    addr: addr + len - 1,
+                });
+                false
+            }
+        }
+    }
+
+    /// Read a byte of memory, wrapping at 4K. Use `check_access` first.
+    fn read_memory(&mut self, addr: usize, flag: u8) -> u8 {
+        let addr = addr % MEMORY_SIZE;
+        self.record_access(addr, flag);
+        self.memory[addr]
+    }
+
+    /// Write a byte of memory, wrapping at 4K. Use `check_access` first.
+    fn write_memory(&mut self, addr: usize, value: u8) {
+        let addr = addr % MEMORY_SIZE;
+        self.record_access(addr, coverage::WRITTEN);
+        self.memory[addr] = value;
+    }
+
     /// Get the state of a pixel (On/Off).
     pub fn get_pixel(&self, pixel_index: usize) -> u8 {
         let triplet_index = pixel_index * 3;
@@ -189,26 +378,82 @@ impl CPU {
         }
     }
 
+    /// Maps an SDL2 Keycode on the numeric keypad to the hex digit it
+    /// represents on the CHIP-8X second keypad.
+    pub fn keycode_to_hex2(&self, key: Keycode) -> Option<u8> {
+        match key {
+            Keycode::Kp0 => Some(0x0),
+            Keycode::Kp1 => Some(0x1),
+            Keycode::Kp2 => Some(0x2),
+            Keycode::Kp3 => Some(0x3),
+            Keycode::Kp4 => Some(0x4),
+            Keycode::Kp5 => Some(0x5),
+            Keycode::Kp6 => Some(0x6),
+            Keycode::Kp7 => Some(0x7),
+            Keycode::Kp8 => Some(0x8),
+            Keycode::Kp9 => Some(0x9),
+            Keycode::KpDivide => Some(0xA),
+            Keycode::KpMultiply => Some(0xB),
+            Keycode::KpMinus => Some(0xC),
+            Keycode::KpPlus => Some(0xD),
+            Keycode::KpEnter => Some(0xE),
+            Keycode::KpPeriod => Some(0xF),
+            _ => None,
+        }
+    }
+
     /// Update the keypad to reflect a keypress.
     pub fn update_keypad(&mut self, key: sdl2::keyboard::Keycode, key_down: bool) {
         if let Some(hex) = self.keycode_to_hex(key) {
             self.keypad[hex as usize] = key_down as u8;
         }
+        if let Some(hex) = self.keycode_to_hex2(key) {
+            self.keypad2[hex as usize] = key_down as u8;
+        }
+    }
+
+    /// Hand the key a `Fx0A` is waiting for to the program.
+    pub fn finish_waitkey(&mut self, key: u8) {
+        self.v_reg[self.opcode.x()] = key;
+        self.waitkey = false;
+    }
+
+    /// Whether the CPU is waiting for a key or the next frame, and won't
+    /// execute anything until it gets it.
+    pub fn is_waiting(&self) -> bool {
+        self.waitkey || self.vblank_wait
+    }
+
+    /// Tell the CPU a new 60 Hz frame has started: the timers count down,
+    /// and any wait for the frame ends. The timers run at 60 Hz however
+    /// many instructions fit in a frame.
+    pub fn vblank(&mut self) {
+        self.vblank_wait = false;
+        self.update_timers();
     }
 
-    /// Emulate a CPU cycle.
+    /// Emulate a CPU cycle. Does nothing once the CPU has faulted.
     pub fn emulate_cycle(&mut self) {
+        if self.fault.is_some() {
+            return;
+        }
         self.fetch_opcode();
+        if self.fault.is_some() {
+            return;
+        }
         // println!("{:X}", self.opcode);
         self.decode_opcode();
-        self.update_timers();
     }
 
     /// Fetch the next opcode by merging the next two bytes at the PC.
     fn fetch_opcode(&mut self) {
+        if !self.check_access(self.pc, 2) {
+            return;
+        }
+
         // Bytes are cast into u16 so we can merge them next
-        let byte1 = self.memory[self.pc] as u16;
-        let byte2 = self.memory[self.pc + 1] as u16;
+        let byte1 = self.read_memory(self.pc, coverage::EXECUTED) as u16;
+        let byte2 = self.read_memory(self.pc + 1, coverage::EXECUTED) as u16;
 
         // Merge the 2-byte instruction at the program counter
         self.opcode = (byte1 << 8) | byte2;
@@ -220,13 +465,58 @@ impl CPU {
         self.pc += 2;
     }
 
+    /// The addresses of the CALL instructions we're currently inside,
+    /// outermost first.
+    pub fn call_stack(&self) -> &[usize] {
+        &self.stack[..self.sp]
+    }
+
+    /// A readable backtrace: the current instruction followed by each CALL
+    /// that led to it, innermost first.
+    pub fn backtrace(&self) -> String {
+        let mut frames = vec![self.pc];
+        frames.extend(self.call_stack().iter().rev());
+
+        let mut out = String::new();
+        for (depth, &addr) in frames.iter().enumerate() {
+            let text = disasm::opcode_at(&self.memory, addr)
+                .map_or_else(|| "??".to_string(), disasm::disassemble);
+            out.push_str(&format!("  #{:<2} 0x{:03X}  {}\n", depth, addr, text));
+        }
+        out
+    }
+
     /// (00EE) Return from a subroutine.
     fn opcode_ret(&mut self) {
+        if self.sp == 0 {
+            match self.quirks.stack {
+                StackPolicy::Fault => {
+                    self.fault = Some(Fault::StackUnderflow { pc: self.pc });
+                    return;
+                }
+                StackPolicy::Wrap => self.sp = STACK_SIZE,
+            }
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
         self.pc += 2;
     }
 
+    /// (0nnn) Call a machine code routine. Only a registered host routine
+    /// can stand in for it; otherwise the `sys` quirk says what happens.
+    fn opcode_sys(&mut self) {
+        let addr = self.opcode.nnn();
+        if let Some(&routine) = self.sys_routines.get(&addr) {
+            self.pc += 2;
+            routine(self);
+            return;
+        }
+        match self.quirks.sys {
+            SysPolicy::Ignore => self.pc += 2,
+            SysPolicy::Fault => self.fault = Some(Fault::MachineCode { pc: self.pc, addr }),
+        }
+    }
+
     /// (1nnn) Jump to location.
     fn opcode_jp(&mut self) {
         self.pc = self.opcode.nnn();
@@ -234,6 +524,15 @@ impl CPU {
 
     /// (2nnn) Call subroutine.
     fn opcode_call(&mut self) {
+        if self.sp == STACK_SIZE {
+            match self.quirks.stack {
+                StackPolicy::Fault => {
+                    self.fault = Some(Fault::StackOverflow { pc: self.pc });
+                    return;
+                }
+                StackPolicy::Wrap => self.sp = 0,
+            }
+        }
         self.stack[self.sp] = self.pc;
         self.sp += 1;
         self.pc = self.opcode.nnn();
@@ -306,14 +605,15 @@ impl CPU {
 
         let (result, overflow) = vx.overflowing_add(vy);
 
+        // The flag goes in after the result, so it wins when x is F
+        self.v_reg[self.opcode.x()] = result;
+
         // Set carry flag
         if overflow {
             self.v_reg[0xF] = 1;
         } else {
             self.v_reg[0xF] = 0;
         }
-
-        self.v_reg[self.opcode.x()] = result;
         self.pc += 2;
     }
 
@@ -324,13 +624,12 @@ impl CPU {
 
         let (result, overflow) = vx.overflowing_sub(vy);
 
+        self.v_reg[self.opcode.x()] = result;
         if overflow {
             self.v_reg[0xF] = 0;
         } else {
             self.v_reg[0xF] = 1;
         }
-
-        self.v_reg[self.opcode.x()] = result;
         self.pc += 2;
     }
 
@@ -338,8 +637,8 @@ impl CPU {
     fn opcode_shr(&mut self) {
         let lsb = self.v_reg[self.opcode.x()] & 0x01;
 
-        self.v_reg[0xF] = lsb;
         self.v_reg[self.opcode.x()] >>= 1;
+        self.v_reg[0xF] = lsb;
         self.pc += 2;
     }
 
@@ -350,23 +649,22 @@ impl CPU {
 
         let (result, overflow) = vy.overflowing_sub(vx);
 
+        self.v_reg[self.opcode.x()] = result;
         if overflow {
             self.v_reg[0xF] = 0;
         } else {
             self.v_reg[0xF] = 1;
         }
-
-        self.v_reg[self.opcode.x()] = result;
         self.pc += 2;
     }
 
     /// (8xyE) Left shift.
     fn opcode_shl(&mut self) {
         // 0x8 = 0b1000
-        let msb = self.v_reg[self.opcode.x()] & 0x80;
+        let msb = (self.v_reg[self.opcode.x()] & 0x80) >> 7;
 
-        self.v_reg[0xF] = msb;
         self.v_reg[self.opcode.x()] <<= 1;
+        self.v_reg[0xF] = msb;
         self.pc += 2;
     }
 
@@ -394,8 +692,7 @@ impl CPU {
 
     /// Generate random byte AND kk, store in Vx
     fn opcode_rnd(&mut self) {
-        let mut rng = thread_rng();
-        let random_num: u8 = rng.gen(); // Generates a random u8 number
+        let random_num: u8 = self.rng.gen(); // Generates a random u8 number
 
         self.v_reg[self.opcode.x()] = random_num & self.opcode.kk();
         self.pc += 2;
@@ -403,12 +700,15 @@ impl CPU {
 
     /// (Dxyn) Draw an n-byte sprite at (Vx, Vy) from memory location I
     fn opcode_drw(&mut self) {
-        let xcoord = self.v_reg[self.opcode.x()] as usize;
-        let ycoord = self.v_reg[self.opcode.y()] as usize;
+        // Sprites that start off screen wrap around to the other side
+        let height = self.display_height();
+        let xcoord = self.v_reg[self.opcode.x()] as usize % DISPLAY_WIDTH;
+        let ycoord = self.v_reg[self.opcode.y()] as usize % height;
         let sprite_height = self.opcode.n();
 
-        // The index of the pixel where we start drawing from.
-        let starting_pixel_index = xcoord + (ycoord * DISPLAY_WIDTH);
+        if !self.check_access(self.i_addr, sprite_height) {
+            return;
+        }
 
         // Set collision flag off, we'll turn it on if we get a collision
         // at any point while drawing.
@@ -416,27 +716,34 @@ impl CPU {
 
         // For each row in the sprite...
         for row_number in 0..sprite_height as usize {
+            // Rows past the bottom wrap to the top, or are clipped
+            let mut y = ycoord + row_number;
+            if y >= height {
+                if self.quirks.sprite_y == EdgePolicy::Clip {
+                    break;
+                }
+                y -= height;
+            }
+
             // The actual pixels of this row for the sprite
-            let sprite_row: u8 = self.memory[self.i_addr + row_number];
+            let sprite_row: u8 = self.read_memory(self.i_addr + row_number, coverage::READ);
 
             // For each pixel in the sprite row...
             for pixel_number in 0..8 as usize {
+                // Pixels past the right edge wrap to the left, or are clipped
+                let mut x = xcoord + pixel_number;
+                if x >= DISPLAY_WIDTH {
+                    if self.quirks.sprite_x == EdgePolicy::Clip {
+                        break;
+                    }
+                    x -= DISPLAY_WIDTH;
+                }
+
                 // We use masking to go through each bit in the row.
                 let sprite_pixel = if (sprite_row & (0x80 >> pixel_number)) == 0 { 0 } else { 1 };
 
                 // The pixel we are about to write to.
-                let mut target_pixel_index =
-                    starting_pixel_index + (row_number * DISPLAY_WIDTH) + pixel_number;
-
-                // Handle overflow by wrapping to the start of the row.
-                if (starting_pixel_index + pixel_number) >= DISPLAY_WIDTH {
-                    target_pixel_index -= DISPLAY_WIDTH;
-                }
-
-                // Handle vertical wrapping.
-                if target_pixel_index >= DISPLAY_SIZE {
-                    target_pixel_index -= DISPLAY_SIZE;
-                }
+                let target_pixel_index = x + y * DISPLAY_WIDTH;
 
                 // Set collision flag if a collision happened.
                 if (sprite_pixel == 1) && (self.get_pixel(target_pixel_index) == 1) {
@@ -448,11 +755,13 @@ impl CPU {
             }
         }
         self.pc += 2;
+        self.vblank_wait = self.quirks.display_wait;
     }
 
     /// (Ex9E) Skip next instruction if key with value Vx pressed.
     fn opcode_skp(&mut self) {
-        let vx = self.v_reg[self.opcode.x()];
+        // Only the low nibble names a key
+        let vx = self.v_reg[self.opcode.x()] & 0xF;
 
         if self.keypad[vx as usize] == 1 {
             self.pc += 2;
@@ -462,7 +771,7 @@ impl CPU {
 
     /// (ExA1) Skip next instruction if key with value Vx not pressed.
     fn opcode_sknp(&mut self) {
-        let vx = self.v_reg[self.opcode.x()];
+        let vx = self.v_reg[self.opcode.x()] & 0xF;
 
         if self.keypad[vx as usize] == 0 {
             self.pc += 2;
@@ -508,7 +817,7 @@ impl CPU {
         // Digit sprites are 5 bytes long starting at 0x0, so we multiply to
         // get the address.
         // 0 * 5 = 0. 1 * 5 = 5. 0xF * 5 = 75 etc.
-        self.i_addr = FONTSET_START + ((vx * 5) as usize);
+        self.i_addr = FONTSET_START + (vx as usize) * 5;
         self.pc += 2;
     }
 
@@ -524,9 +833,12 @@ impl CPU {
         let tens = (vx - (hundreds * 100)) / 10;
         let ones = vx - (hundreds * 100) - (tens * 10);
 
-        self.memory[self.i_addr + 0] = hundreds;
-        self.memory[self.i_addr + 1] = tens;
-        self.memory[self.i_addr + 2] = ones;
+        if !self.check_access(self.i_addr, 3) {
+            return;
+        }
+        self.write_memory(self.i_addr, hundreds);
+        self.write_memory(self.i_addr + 1, tens);
+        self.write_memory(self.i_addr + 2, ones);
         self.pc += 2;
     }
 
@@ -534,8 +846,12 @@ impl CPU {
     fn opcode_store_vx(&mut self) {
         let x = self.opcode.x();
 
+        if !self.check_access(self.i_addr, x + 1) {
+            return;
+        }
         for i in 0..=x {
-            self.memory[self.i_addr + i] = self.v_reg[i];
+            let value = self.v_reg[i];
+            self.write_memory(self.i_addr + i, value);
         }
         self.pc += 2;
     }
@@ -544,72 +860,93 @@ impl CPU {
     fn opcode_read_vx(&mut self) {
         let x = self.opcode.x();
 
+        if !self.check_access(self.i_addr, x + 1) {
+            return;
+        }
         for i in 0..=x {
-            self.v_reg[i] = self.memory[self.i_addr + i];
+            self.v_reg[i] = self.read_memory(self.i_addr + i, coverage::READ);
         }
         self.pc += 2;
     }
 
+    /// Any opcode we don't know.
+    fn opcode_unknown(&mut self) {
+        self.fault = Some(Fault::UnknownOpcode {
+            pc: self.pc,
+            opcode: self.opcode,
+        });
+    }
+
     // ----- End of opcodes ----- //
 
-    fn decode_opcode(&mut self) {
-        match self.opcode & 0xF000 {
-            0x0000 => match self.opcode & 0x00FF {
-                0x00E0 => self.opcode_cls(),
-                0x00EE => self.opcode_ret(),
-                _ => panic!("unknown opcode 0x{:x}", self.opcode),
+    /// Look up the function that executes an opcode on `platform`.
+    fn decode(platform: Platform, opcode: u16) -> fn(&mut CPU) {
+        if let Some(handler) = platform::decode(platform, opcode) {
+            return handler;
+        }
+
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00E0 => CPU::opcode_cls,
+                0x00EE => CPU::opcode_ret,
+                _ => CPU::opcode_sys,
             },
 
-            0x1000 => self.opcode_jp(),
-            0x2000 => self.opcode_call(),
-            0x3000 => self.opcode_se_byte(),
-            0x4000 => self.opcode_sne_byte(),
-            0x5000 => self.opcode_se_vx(),
-            0x6000 => self.opcode_ld_byte(),
-            0x7000 => self.opcode_add_byte(),
-
-            0x8000 => match self.opcode & 0x000F {
-                0x0000 => self.opcode_ld_vy(),
-                0x0001 => self.opcode_or(),
-                0x0002 => self.opcode_and(),
-                0x0003 => self.opcode_xor(),
-                0x0004 => self.opcode_add(),
-                0x0005 => self.opcode_sub(),
-                0x0006 => self.opcode_shr(),
-                0x0007 => self.opcode_subn(),
-                0x000E => self.opcode_shl(),
-                _ => panic!("unknown opcode 0x{:x}", self.opcode),
+            0x1000 => CPU::opcode_jp,
+            0x2000 => CPU::opcode_call,
+            0x3000 => CPU::opcode_se_byte,
+            0x4000 => CPU::opcode_sne_byte,
+            0x5000 => CPU::opcode_se_vx,
+            0x6000 => CPU::opcode_ld_byte,
+            0x7000 => CPU::opcode_add_byte,
+
+            0x8000 => match opcode & 0x000F {
+                0x0000 => CPU::opcode_ld_vy,
+                0x0001 => CPU::opcode_or,
+                0x0002 => CPU::opcode_and,
+                0x0003 => CPU::opcode_xor,
+                0x0004 => CPU::opcode_add,
+                0x0005 => CPU::opcode_sub,
+                0x0006 => CPU::opcode_shr,
+                0x0007 => CPU::opcode_subn,
+                0x000E => CPU::opcode_shl,
+                _ => CPU::opcode_unknown,
             },
 
-            0x9000 => self.opcode_sne(),
-            0xA000 => self.opcode_ld(),
-            0xB000 => self.opcode_jp_v0(),
-            0xC000 => self.opcode_rnd(),
-            0xD000 => self.opcode_drw(),
+            0x9000 => CPU::opcode_sne,
+            0xA000 => CPU::opcode_ld,
+            0xB000 => CPU::opcode_jp_v0,
+            0xC000 => CPU::opcode_rnd,
+            0xD000 => CPU::opcode_drw,
 
-            0xE000 => match self.opcode & 0xF0FF {
-                0xE09E => self.opcode_skp(),
-                0xE0A1 => self.opcode_sknp(),
-                _ => panic!("unknown opcode 0x{:x}", self.opcode),
+            0xE000 => match opcode & 0xF0FF {
+                0xE09E => CPU::opcode_skp,
+                0xE0A1 => CPU::opcode_sknp,
+                _ => CPU::opcode_unknown,
             },
 
-            0xF000 => match self.opcode & 0xF0FF {
-                0xF007 => self.opcode_get_dt(),
-                0xF00A => self.opcode_waitkey(),
-                0xF015 => self.opcode_set_dt(),
-                0xF018 => self.opcode_set_st(),
-                0xF01E => self.opcode_add_i(),
-                0xF029 => self.opcode_set_sprite(),
-                0xF033 => self.opcode_bcd_vx(),
-                0xF055 => self.opcode_store_vx(),
-                0xF065 => self.opcode_read_vx(),
-                _ => panic!("unknown opcode 0x{:x}", self.opcode),
+            0xF000 => match opcode & 0xF0FF {
+                0xF007 => CPU::opcode_get_dt,
+                0xF00A => CPU::opcode_waitkey,
+                0xF015 => CPU::opcode_set_dt,
+                0xF018 => CPU::opcode_set_st,
+                0xF01E => CPU::opcode_add_i,
+                0xF029 => CPU::opcode_set_sprite,
+                0xF033 => CPU::opcode_bcd_vx,
+                0xF055 => CPU::opcode_store_vx,
+                0xF065 => CPU::opcode_read_vx,
+                _ => CPU::opcode_unknown,
             },
 
-            _ => panic!("unknown opcode 0x{:x}", self.opcode),
+            _ => CPU::opcode_unknown,
         }
     }
 
+    fn decode_opcode(&mut self) {
+        let handler = CPU::decode(self.platform, self.opcode);
+        handler(self);
+    }
+
     fn update_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
@@ -624,6 +961,22 @@ impl CPU {
 mod tests {
     use super::*;
 
+    /// A CPU on `platform` with `program` loaded where it starts, for tests
+    /// all over the crate.
+    pub fn cpu_with(platform: Platform, program: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_platform(platform);
+        cpu.load_program(program);
+        cpu
+    }
+
+    /// Golden image for a test, in `tests/golden`.
+    fn golden_path(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.txt", name))
+    }
+
     #[test]
     fn initialize() {
         let c = CPU::new();
@@ -690,6 +1043,75 @@ mod tests {
         assert_eq!(c.sp, 0);
     }
 
+    #[test]
+    fn stack_underflow() {
+        let mut c = CPU::new();
+
+        c.opcode = 0x00EE;
+        c.decode_opcode();
+
+        assert_eq!(c.fault, Some(Fault::StackUnderflow { pc: 0x200 }));
+        assert_eq!(c.pc, 0x200);
+        assert_eq!(c.sp, 0);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut c = CPU::new();
+
+        // A subroutine that calls itself forever
+        c.memory[0x200] = 0x22;
+        c.memory[0x201] = 0x00;
+        for _ in 0..STACK_SIZE + 5 {
+            c.emulate_cycle();
+        }
+
+        assert_eq!(c.fault, Some(Fault::StackOverflow { pc: 0x200 }));
+        assert_eq!(c.sp, STACK_SIZE);
+    }
+
+    #[test]
+    fn stack_wrap() {
+        let mut c = CPU::new();
+        c.quirks.stack = StackPolicy::Wrap;
+
+        c.memory[0x200] = 0x22;
+        c.memory[0x201] = 0x00;
+        for _ in 0..STACK_SIZE + 1 {
+            c.emulate_cycle();
+        }
+        assert_eq!(c.fault, None);
+        assert_eq!(c.sp, 1);
+
+        c.sp = 0;
+        c.stack[STACK_SIZE - 1] = 0x300;
+        c.opcode = 0x00EE;
+        c.decode_opcode();
+        assert_eq!(c.fault, None);
+        assert_eq!(c.pc, 0x302);
+        assert_eq!(c.sp, STACK_SIZE - 1);
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut c = CPU::new();
+
+        c.memory[0x200] = 0x23; // CALL 0x300
+        c.memory[0x201] = 0x00;
+        c.memory[0x300] = 0x24; // CALL 0x400
+        c.memory[0x301] = 0x00;
+        c.memory[0x400] = 0x00; // CLS
+        c.memory[0x401] = 0xE0;
+        c.emulate_cycle();
+        c.emulate_cycle();
+
+        assert_eq!(c.call_stack(), &[0x200, 0x300]);
+        assert_eq!(
+            c.backtrace(),
+            "  #0  0x400  CLS\n  #1  0x300  CALL 0x400\n  #2  0x200  CALL 0x300\n"
+        );
+    }
+
     #[test] // 1nnn
     fn opcode_jp() {
         let mut c = CPU::new();
@@ -785,40 +1207,214 @@ mod tests {
     }
 
     #[test]
-    fn opcode_or() {}
+    fn opcode_or() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0b1100;
+        c.v_reg[0x2] = 0b1010;
+        c.opcode = 0x8121;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0b1110);
+    }
+
+    #[test]
+    fn opcode_and() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0b1100;
+        c.v_reg[0x2] = 0b1010;
+        c.opcode = 0x8122;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0b1000);
+    }
+
+    #[test]
+    fn opcode_xor() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0b1100;
+        c.v_reg[0x2] = 0b1010;
+        c.opcode = 0x8123;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0b0110);
+    }
 
     #[test]
-    fn opcode_and() {}
+    fn opcode_add() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0xF0;
+        c.v_reg[0x2] = 0x20;
+        c.opcode = 0x8124;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0x10);
+        assert_eq!(c.v_reg[0xF], 1, "carry should be set");
+    }
 
     #[test]
-    fn opcode_xor() {}
+    fn opcode_sub() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0x10;
+        c.v_reg[0x2] = 0x20;
+        c.opcode = 0x8125;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0xF0);
+        assert_eq!(c.v_reg[0xF], 0, "borrow should clear VF");
+    }
 
     #[test]
-    fn opcode_add() {}
+    fn opcode_shr() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0b101;
+        c.opcode = 0x8106;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0b10);
+        assert_eq!(c.v_reg[0xF], 1);
+    }
 
     #[test]
-    fn opcode_sub() {}
+    fn opcode_subn() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0x10;
+        c.v_reg[0x2] = 0x30;
+        c.opcode = 0x8127;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0x20);
+        assert_eq!(c.v_reg[0xF], 1, "no borrow should set VF");
+    }
 
     #[test]
-    fn opcode_shr() {}
+    fn opcode_shl() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x1] = 0x81;
+        c.opcode = 0x810E;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x1], 0x02);
+        assert_eq!(c.v_reg[0xF], 1);
+    }
+
+    /// Reference model for the `8xyN` group: the new Vx, and VF if the
+    /// instruction sets it.
+    fn alu_reference(n: u16, vx: u8, vy: u8) -> (u8, Option<u8>) {
+        let (vx, vy) = (vx as u16, vy as u16);
+        match n {
+            0x0 => (vy as u8, None),
+            0x1 => ((vx | vy) as u8, None),
+            0x2 => ((vx & vy) as u8, None),
+            0x3 => ((vx ^ vy) as u8, None),
+            0x4 => ((vx + vy) as u8, Some((vx + vy > 0xFF) as u8)),
+            0x5 => ((vx + 0x100 - vy) as u8, Some((vx >= vy) as u8)),
+            0x6 => ((vx >> 1) as u8, Some((vx & 1) as u8)),
+            0x7 => ((vy + 0x100 - vx) as u8, Some((vy >= vx) as u8)),
+            0xE => ((vx << 1) as u8, Some((vx >> 7) as u8)),
+            _ => unreachable!(),
+        }
+    }
 
     #[test]
-    fn opcode_subn() {}
+    fn alu_matches_reference() {
+        for &n in &[0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE] {
+            for vx in 0..=255 {
+                for vy in 0..=255 {
+                    let mut c = CPU::new();
+                    c.v_reg[0x3] = vx;
+                    c.v_reg[0x9] = vy;
+                    c.v_reg[0xF] = 0xAA;
+                    c.opcode = 0x8390 | n;
+                    c.decode_opcode();
+
+                    let (result, flag) = alu_reference(n, vx, vy);
+                    let op = format!("{:04X} with {:02X}, {:02X}", c.opcode, vx, vy);
+                    assert_eq!(c.v_reg[0x3], result, "{}", op);
+                    assert_eq!(c.v_reg[0xF], flag.unwrap_or(0xAA), "{}", op);
+                    assert_eq!(c.v_reg[0x9], vy, "{}", op);
+                    assert_eq!(c.pc, 0x202);
+                }
+            }
+        }
+    }
 
     #[test]
-    fn opcode_shl() {}
+    fn alu_flag_overrides_result() {
+        // When Vx is VF, the result is written first and the flag wins,
+        // like on the COSMAC VIP
+        let mut rng = SmallRng::from_seed([0xC8; 16]);
+        for &n in &[0x4, 0x5, 0x6, 0x7, 0xE] {
+            for _ in 0..1000 {
+                let (vx, vy) = (rng.gen(), rng.gen());
+                let mut c = CPU::new();
+                c.v_reg[0xF] = vx;
+                c.v_reg[0x2] = vy;
+                c.opcode = 0x8F20 | n;
+                c.decode_opcode();
+
+                assert_eq!(c.v_reg[0xF], alu_reference(n, vx, vy).1.unwrap());
+            }
+        }
+    }
 
     #[test]
-    fn opcode_sne() {}
+    fn opcode_sne() {
+        let mut c = CPU::new();
+
+        let old_pc = c.pc;
+        c.v_reg[0xA] = 1;
+        c.v_reg[0xB] = 2;
+        c.opcode = 0x9AB0;
+        c.decode_opcode();
+
+        assert_eq!(c.pc, old_pc + 4);
+    }
 
     #[test]
-    fn opcode_ld() {}
+    fn opcode_ld() {
+        let mut c = CPU::new();
+
+        c.opcode = 0xA123;
+        c.decode_opcode();
+
+        assert_eq!(c.i_addr, 0x123);
+    }
 
     #[test]
-    fn opcode_jp_v0() {}
+    fn opcode_jp_v0() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x0] = 0x10;
+        c.opcode = 0xB300;
+        c.decode_opcode();
+
+        assert_eq!(c.pc, 0x310);
+    }
 
     #[test]
-    fn opcode_rnd() {}
+    fn opcode_rnd() {
+        let mut a = CPU::new();
+        let mut b = CPU::new();
+        a.seed_rng(42);
+        b.seed_rng(42);
+
+        a.opcode = 0xC30F;
+        b.opcode = 0xC30F;
+        for _ in 0..8 {
+            a.decode_opcode();
+            b.decode_opcode();
+            assert_eq!(a.v_reg[3], b.v_reg[3]);
+            assert_eq!(a.v_reg[3] & 0xF0, 0);
+        }
+    }
 
     #[test]
     fn opcode_drw() {
@@ -841,12 +1437,143 @@ mod tests {
 
         c.decode_opcode();
 
-        assert_eq!(c.get_pixel(DISPLAY_WIDTH - 1), 0, "pixel wasn't zeroed");
-        assert_eq!(c.get_pixel(DISPLAY_WIDTH * 2 - 1), 1);
         assert_eq!(c.v_reg[0xF], 1, "carry bit should be set by collision");
-        // wrapping
-        assert_eq!(c.get_pixel(0), 1, "sprite should wrap");
-        assert_eq!(c.get_pixel(DISPLAY_WIDTH), 1, "sprite should wrap ");
+        // The pixel at (63, 0) is cleared and the rest of the cube wraps
+        golden::assert_golden(&c, &golden_path("opcode_drw"));
+    }
+
+    /// Draw a sprite at (x, y) with the given edge policies, and return the
+    /// (x, y) of every pixel that's on.
+    fn drw_at(
+        x: u8,
+        y: u8,
+        sprite: &[u8],
+        sprite_x: EdgePolicy,
+        sprite_y: EdgePolicy,
+    ) -> Vec<(usize, usize)> {
+        let mut c = CPU::new();
+        c.quirks.sprite_x = sprite_x;
+        c.quirks.sprite_y = sprite_y;
+        c.v_reg[0] = x;
+        c.v_reg[1] = y;
+        c.i_addr = 0x300;
+        c.memory[0x300..0x300 + sprite.len()].copy_from_slice(sprite);
+        c.opcode = 0xD010 | sprite.len() as u16;
+        c.decode_opcode();
+
+        (0..DISPLAY_SIZE)
+            .filter(|&pixel| c.get_pixel(pixel) == 1)
+            .map(|pixel| (pixel % DISPLAY_WIDTH, pixel / DISPLAY_WIDTH))
+            .collect()
+    }
+
+    #[test]
+    fn drw_right_edge() {
+        use quirks::EdgePolicy::{Clip, Wrap};
+
+        // 0b10000001 at x = 60 reaches x = 67
+        assert_eq!(drw_at(60, 5, &[0x81], Wrap, Wrap), vec![(3, 5), (60, 5)]);
+        assert_eq!(drw_at(60, 5, &[0x81], Clip, Wrap), vec![(60, 5)]);
+        // The other axis doesn't matter
+        assert_eq!(drw_at(60, 5, &[0x81], Wrap, Clip), vec![(3, 5), (60, 5)]);
+    }
+
+    #[test]
+    fn drw_bottom_edge() {
+        use quirks::EdgePolicy::{Clip, Wrap};
+
+        let sprite = [0x80, 0x00, 0x80];
+        assert_eq!(drw_at(7, 30, &sprite, Wrap, Wrap), vec![(7, 0), (7, 30)]);
+        assert_eq!(drw_at(7, 30, &sprite, Wrap, Clip), vec![(7, 30)]);
+        assert_eq!(drw_at(7, 30, &sprite, Clip, Wrap), vec![(7, 0), (7, 30)]);
+    }
+
+    #[test]
+    fn drw_corner() {
+        use quirks::EdgePolicy::{Clip, Wrap};
+
+        let sprite = [0xFF, 0xFF];
+        assert_eq!(drw_at(63, 31, &sprite, Clip, Clip), vec![(63, 31)]);
+        let wrapped = drw_at(63, 31, &sprite, Wrap, Wrap);
+        assert_eq!(wrapped.len(), 16);
+        assert!(wrapped.contains(&(6, 0)));
+    }
+
+    #[test]
+    fn drw_starts_beyond_edges() {
+        use quirks::EdgePolicy::{Clip, Wrap};
+
+        // (70, 40) is (6, 8) whatever the policy
+        for &sprite_x in &[Wrap, Clip] {
+            for &sprite_y in &[Wrap, Clip] {
+                assert_eq!(drw_at(70, 40, &[0x80], sprite_x, sprite_y), vec![(6, 8)]);
+            }
+        }
+        // Wrapped round to the last column, then clipped
+        assert_eq!(drw_at(127, 0, &[0xC0], Clip, Clip), vec![(63, 0)]);
+        assert_eq!(drw_at(127, 0, &[0xC0], Wrap, Wrap), vec![(0, 0), (63, 0)]);
+    }
+
+    #[test]
+    fn drw_clipped_collision() {
+        let mut c = CPU::new();
+        c.quirks.sprite_x = EdgePolicy::Clip;
+        // Only a clipped pixel would collide
+        c.set_pixel(0, 1);
+        c.v_reg[0] = 63;
+        c.i_addr = 0x300;
+        c.memory[0x300] = 0x40;
+        c.opcode = 0xD011;
+        c.decode_opcode();
+        assert_eq!(c.v_reg[0xF], 0);
+        assert_eq!(c.get_pixel(0), 1);
+    }
+
+    #[test] // 0nnn
+    fn opcode_sys() {
+        let mut c = CPU::new();
+        c.opcode = 0x0123;
+        c.decode_opcode();
+        assert_eq!(
+            c.fault,
+            Some(Fault::MachineCode {
+                pc: 0x200,
+                addr: 0x123
+            })
+        );
+
+        let mut c = CPU::new();
+        c.quirks.sys = SysPolicy::Ignore;
+        c.opcode = 0x0123;
+        c.decode_opcode();
+        assert_eq!(c.fault, None);
+        assert_eq!(c.pc, 0x202);
+
+        // 01E0 isn't CLS
+        c.display[0] = 1;
+        c.opcode = 0x01E0;
+        c.decode_opcode();
+        assert_eq!(c.display[0], 1);
+    }
+
+    #[test]
+    fn sys_routines() {
+        fn set_vf(c: &mut CPU) {
+            c.v_reg[0xF] = 0x42;
+        }
+
+        let mut c = CPU::new();
+        c.register_sys(0x123, set_vf);
+        c.opcode = 0x0123;
+        c.decode_opcode();
+        assert_eq!(c.fault, None);
+        assert_eq!(c.v_reg[0xF], 0x42);
+        assert_eq!(c.pc, 0x202);
+
+        // Other addresses still follow the policy
+        c.opcode = 0x0124;
+        c.decode_opcode();
+        assert!(c.fault.is_some());
     }
 
     #[test]
@@ -880,19 +1607,62 @@ mod tests {
     }
 
     #[test]
-    fn opcode_ld_set_dt() {}
+    fn opcode_ld_set_dt() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x4] = 60;
+        c.opcode = 0xF415;
+        c.decode_opcode();
+
+        assert_eq!(c.delay_timer, 60);
+    }
 
     #[test]
-    fn opcode_ld_k() {}
+    fn opcode_ld_k() {
+        let mut c = CPU::new();
+
+        c.opcode = 0xF50A;
+        c.decode_opcode();
+        assert!(c.waitkey);
+
+        c.finish_waitkey(0xB);
+        assert!(!c.waitkey);
+        assert_eq!(c.v_reg[0x5], 0xB);
+    }
 
     #[test]
-    fn opcode_ld_get_dt() {}
+    fn opcode_ld_get_dt() {
+        let mut c = CPU::new();
+
+        c.delay_timer = 42;
+        c.opcode = 0xF607;
+        c.decode_opcode();
+
+        assert_eq!(c.v_reg[0x6], 42);
+    }
 
     #[test]
-    fn opcode_set_st() {}
+    fn opcode_set_st() {
+        let mut c = CPU::new();
+
+        c.v_reg[0x7] = 30;
+        c.opcode = 0xF718;
+        c.decode_opcode();
+
+        assert_eq!(c.sound_timer, 30);
+    }
 
     #[test]
-    fn opcode_add_i() {}
+    fn opcode_add_i() {
+        let mut c = CPU::new();
+
+        c.i_addr = 0x300;
+        c.v_reg[0x8] = 0x25;
+        c.opcode = 0xF81E;
+        c.decode_opcode();
+
+        assert_eq!(c.i_addr, 0x325);
+    }
 
     #[test]
     fn opcode_set_sprite() {
@@ -958,6 +1728,73 @@ mod tests {
         assert_eq!(c.v_reg[0x2], 0xDD);
     }
 
+    #[test]
+    fn memory_wrap() {
+        let mut c = CPU::new();
+
+        c.v_reg[0] = 0x11;
+        c.v_reg[1] = 0x22;
+        c.v_reg[2] = 0x33;
+        c.i_addr = 0xFFE;
+        c.opcode = 0xF255;
+        c.decode_opcode();
+
+        assert_eq!(c.fault, None);
+        assert_eq!(c.memory[0xFFE], 0x11);
+        assert_eq!(c.memory[0xFFF], 0x22);
+        assert_eq!(c.memory[0x000], 0x33);
+    }
+
+    #[test]
+    fn memory_fault() {
+        let mut c = CPU::new();
+        c.quirks.memory = MemoryPolicy::Fault;
+
+        c.i_addr = 0xFFF;
+        c.opcode = 0xD012;
+        c.decode_opcode();
+
+        assert_eq!(
+            c.fault,
+            Some(Fault::MemoryOutOfBounds {
+                pc: 0x200,
+                addr: 0x1000
+            })
+        );
+        assert_eq!(c.pc, 0x200);
+    }
+
+    #[test]
+    fn fetch_fault() {
+        let mut c = CPU::new();
+        c.quirks.memory = MemoryPolicy::Fault;
+
+        c.pc = 0xFFF;
+        c.emulate_cycle();
+
+        assert_eq!(
+            c.fault,
+            Some(Fault::MemoryOutOfBounds {
+                pc: 0xFFF,
+                addr: 0x1000
+            })
+        );
+    }
+
+    #[test]
+    fn memory_warn() {
+        let mut c = CPU::new();
+        c.quirks.memory = MemoryPolicy::Warn;
+
+        c.memory[0x000] = 0xAB;
+        c.i_addr = 0xFFF;
+        c.opcode = 0xF165;
+        c.decode_opcode();
+
+        assert_eq!(c.fault, None);
+        assert_eq!(c.v_reg[1], 0xAB);
+    }
+
     #[test]
     fn update_keypad() {
         let mut c = CPU::new();
@@ -970,4 +1807,60 @@ mod tests {
         assert_eq!(c.keypad[0xE], 0);
     }
 
+    #[test]
+    fn smc_detection() {
+        let mut c = CPU::new();
+        c.enable_smc_detection();
+
+        c.memory[0x200] = 0xA2; // LD I, 0x200
+        c.memory[0x201] = 0x00;
+        c.memory[0x202] = 0xF0; // LD [I], V0
+        c.memory[0x203] = 0x55;
+        c.memory[0x204] = 0x12; // JP 0x200
+        c.memory[0x205] = 0x00;
+        c.v_reg[0] = 0xA2; // Patch the first instruction with itself
+        for _ in 0..4 {
+            c.emulate_cycle();
+        }
+
+        assert_eq!(
+            c.smc.as_mut().unwrap().take_events(),
+            vec![
+                smc::SmcEvent::CodeWritten {
+                    pc: 0x202,
+                    addr: 0x200
+                },
+                smc::SmcEvent::WrittenExecuted {
+                    pc: 0x200,
+                    addr: 0x200
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn coverage() {
+        let mut c = CPU::new();
+        c.enable_coverage();
+
+        c.memory[0x200] = 0xA3; // LD I, 0x300
+        c.memory[0x201] = 0x00;
+        c.memory[0x202] = 0xF1; // LD [I], V1
+        c.memory[0x203] = 0x55;
+        c.memory[0x204] = 0xD0; // DRW V0, V0, 1
+        c.memory[0x205] = 0x01;
+        for _ in 0..3 {
+            c.emulate_cycle();
+        }
+
+        let coverage = c.coverage.unwrap();
+        assert!(coverage.is_executed(0x200));
+        assert!(coverage.is_executed(0x205));
+        assert!(!coverage.is_executed(0x206));
+        assert!(coverage.is_written(0x300));
+        assert!(coverage.is_written(0x301));
+        assert!(coverage.is_read(0x300));
+        assert!(!coverage.is_read(0x301));
+    }
+
 }
@@ -0,0 +1,354 @@
+//! Lockstep differential testing.
+//!
+//! Runs two machines side by side on the same ROM and input, one instruction
+//! at a time, and compares their state after every step. A step is a whole
+//! block when either backend compiles blocks, since they're only brought up
+//! to date at the end of one. The machines can
+//! differ in backend, quirks, or anything else that's supposed to leave the
+//! result unchanged; the first step where they disagree is reported along
+//! with a disassembly of the code around it.
+
+use std::fmt;
+
+use backend::Backend;
+use disasm;
+use {CPU, DISPLAY_WIDTH, MEMORY_SIZE};
+
+/// How many instructions either side of the PC to show in a divergence
+/// report.
+const CONTEXT: usize = 4;
+
+/// A CPU together with the backend that runs it.
+pub struct Machine {
+    pub cpu: CPU,
+    pub backend: Box<dyn Backend>,
+}
+
+impl Machine {
+    pub fn new(cpu: CPU, backend: Box<dyn Backend>) -> Machine {
+        Machine { cpu, backend }
+    }
+}
+
+/// The first point where two machines disagreed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of instructions executed before the step that diverged.
+    pub step: usize,
+    /// Address of the instruction, or start of the block, that diverged.
+    pub pc: usize,
+    /// One line per piece of state that differs.
+    pub differences: Vec<String>,
+    /// Disassembly of the code around `pc`, with `pc` marked.
+    pub context: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "diverged at step {} (instruction at 0x{:03X}):",
+            self.step, self.pc
+        )?;
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        write!(f, "{}", self.context)
+    }
+}
+
+/// Describe everything that differs between two CPUs. Memory and the
+/// framebuffer are summarised by their first difference.
+pub fn compare(a: &CPU, b: &CPU) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    for (i, (va, vb)) in a.v_reg.iter().zip(b.v_reg.iter()).enumerate() {
+        if va != vb {
+            differences.push(format!("V{:X}: 0x{:02X} != 0x{:02X}", i, va, vb));
+        }
+    }
+    if a.i_addr != b.i_addr {
+        differences.push(format!("I: 0x{:03X} != 0x{:03X}", a.i_addr, b.i_addr));
+    }
+    if a.pc != b.pc {
+        differences.push(format!("PC: 0x{:03X} != 0x{:03X}", a.pc, b.pc));
+    }
+    if a.call_stack() != b.call_stack() {
+        differences.push(format!(
+            "stack: {} != {}",
+            format_stack(a.call_stack()),
+            format_stack(b.call_stack())
+        ));
+    }
+    if a.delay_timer != b.delay_timer {
+        differences.push(format!("DT: {} != {}", a.delay_timer, b.delay_timer));
+    }
+    if a.sound_timer != b.sound_timer {
+        differences.push(format!("ST: {} != {}", a.sound_timer, b.sound_timer));
+    }
+
+    let memory: Vec<usize> = (0..MEMORY_SIZE)
+        .filter(|&addr| a.memory[addr] != b.memory[addr])
+        .collect();
+    if let Some(&addr) = memory.first() {
+        differences.push(format!(
+            "memory: 0x{:03X}: 0x{:02X} != 0x{:02X} ({} bytes differ)",
+            addr,
+            a.memory[addr],
+            b.memory[addr],
+            memory.len()
+        ));
+    }
+
+    let pixels: Vec<usize> = (0..a.display_size())
+        .filter(|&pixel| a.get_pixel(pixel) != b.get_pixel(pixel))
+        .collect();
+    if let Some(&pixel) = pixels.first() {
+        differences.push(format!(
+            "display: ({}, {}): {} != {} ({} pixels differ)",
+            pixel % DISPLAY_WIDTH,
+            pixel / DISPLAY_WIDTH,
+            a.get_pixel(pixel),
+            b.get_pixel(pixel),
+            pixels.len()
+        ));
+    }
+
+    if a.color_board.background() != b.color_board.background() {
+        differences.push(format!(
+            "colour board background: {} != {}",
+            a.color_board.background(),
+            b.color_board.background()
+        ));
+    }
+    let zones = a.color_board.zones.iter().zip(b.color_board.zones.iter());
+    if let Some((zone, (za, zb))) = zones.enumerate().find(|&(_, (za, zb))| za != zb) {
+        differences.push(format!("colour board zone {}: {} != {}", zone, za, zb));
+    }
+
+    if a.keypad2 != b.keypad2 {
+        differences.push(format!("keypad 2: {:?} != {:?}", a.keypad2, b.keypad2));
+    }
+    if a.waitkey != b.waitkey {
+        differences.push(format!("waitkey: {} != {}", a.waitkey, b.waitkey));
+    }
+    if a.vblank_wait != b.vblank_wait {
+        differences.push(format!(
+            "vblank wait: {} != {}",
+            a.vblank_wait, b.vblank_wait
+        ));
+    }
+    if a.delay_wait != b.delay_wait {
+        differences.push(format!("delay wait: {} != {}", a.delay_wait, b.delay_wait));
+    }
+    if a.fault != b.fault {
+        differences.push(format!("fault: {} != {}", format_fault(a), format_fault(b)));
+    }
+
+    differences
+}
+
+fn format_stack(stack: &[usize]) -> String {
+    let addrs: Vec<String> = stack.iter().map(|addr| format!("0x{:03X}", addr)).collect();
+    format!("[{}]", addrs.join(", "))
+}
+
+fn format_fault(cpu: &CPU) -> String {
+    cpu.fault
+        .map_or_else(|| "none".to_string(), |fault| fault.to_string())
+}
+
+/// Disassemble the instructions around `pc`, marking the one at `pc`.
+pub fn context(memory: &[u8], pc: usize) -> String {
+    let start = pc.saturating_sub(CONTEXT * 2);
+    let mut out = String::new();
+    for addr in (start..pc + (CONTEXT + 1) * 2).step_by(2) {
+        if let Some(opcode) = disasm::opcode_at(memory, addr) {
+            let marker = if addr == pc { "=>" } else { "  " };
+            out.push_str(&format!(
+                "{} 0x{:03X}  {:04X}  {}\n",
+                marker,
+                addr,
+                opcode,
+                disasm::disassemble(opcode)
+            ));
+        }
+    }
+    out
+}
+
+/// Two machines run one instruction at a time.
+pub struct Lockstep {
+    pub a: Machine,
+    pub b: Machine,
+    /// Instructions executed so far.
+    pub steps: usize,
+}
+
+impl Lockstep {
+    /// Start comparing two machines. They should already hold the same ROM.
+    pub fn new(a: Machine, b: Machine) -> Lockstep {
+        Lockstep { a, b, steps: 0 }
+    }
+
+    /// Whether neither machine can make progress without help, because
+    /// they've faulted or are waiting for a key or the next frame.
+    pub fn is_stopped(&self) -> bool {
+        let stopped = |cpu: &CPU| cpu.fault.is_some() || cpu.is_waiting();
+        stopped(&self.a.cpu) && stopped(&self.b.cpu)
+    }
+
+    /// Press or release a key on both machines.
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.a.cpu.keypad[key as usize] = pressed as u8;
+        self.b.cpu.keypad[key as usize] = pressed as u8;
+    }
+
+    /// Answer a key wait on both machines.
+    pub fn finish_waitkey(&mut self, key: u8) {
+        self.a.cpu.finish_waitkey(key);
+        self.b.cpu.finish_waitkey(key);
+    }
+
+    /// Start a new frame on both machines.
+    pub fn vblank(&mut self) {
+        self.a.cpu.vblank();
+        self.b.cpu.vblank();
+    }
+
+    /// Execute the next instruction on each machine, or the next block if
+    /// either backend runs a block from here, and compare them. At most
+    /// `limit` instructions are executed. Returns how many were.
+    pub fn step(&mut self, limit: usize) -> Result<usize, Divergence> {
+        let pc = self.a.cpu.pc;
+        let len = self.a.backend.step_len(&self.a.cpu);
+        let len = len.max(self.b.backend.step_len(&self.b.cpu)).min(limit);
+        let executed = self.a.backend.run(&mut self.a.cpu, len);
+        let executed = executed.max(self.b.backend.run(&mut self.b.cpu, len));
+
+        let differences = compare(&self.a.cpu, &self.b.cpu);
+        if !differences.is_empty() {
+            return Err(Divergence {
+                step: self.steps,
+                pc,
+                differences,
+                context: context(&self.a.cpu.memory, pc),
+            });
+        }
+        self.steps += executed;
+        Ok(executed)
+    }
+
+    /// Execute up to `steps` instructions, stopping early if both machines
+    /// stop. Returns how many were executed.
+    pub fn run(&mut self, steps: usize) -> Result<usize, Divergence> {
+        let mut executed = 0;
+        while executed < steps {
+            if self.is_stopped() {
+                break;
+            }
+            executed += self.step(steps - executed)?;
+        }
+        Ok(executed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{CachedInterpreter, Interpreter};
+    use quirks::StackPolicy;
+
+    /// Draws random sprites and stores their BCD, then waits for a key and
+    /// recurses until the stack overflows.
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const ROM: [u8; 20] = [
+        0xC0, 0x1F, // 200: RND V0, 0x1F
+        0xC1, 0x0F, // 202: RND V1, 0x0F
+        0xF0, 0x29, // 204: LD F, V0
+        0xD0, 0x15, // 206: DRW V0, V1, 5
+        0xA3, 0x00, // 208: LD I, 0x300
+        0xF0, 0x33, // 20A: LD B, V0
+        0x72, 0x01, // 20C: ADD V2, 0x01
+        0x32, 0x10, // 20E: SE V2, 0x10
+        0x12, 0x00, // 210: JP 0x200
+        0xF3, 0x0A, // 212: LD V3, K
+    ];
+
+    fn machine(backend: Box<dyn Backend>) -> Machine {
+        let mut cpu = CPU::new();
+        cpu.memory[0x200..0x200 + ROM.len()].copy_from_slice(&ROM);
+        cpu.memory[0x214] = 0x22; // 214: CALL 0x214
+        cpu.memory[0x215] = 0x14;
+        cpu.seed_rng(7);
+        Machine::new(cpu, backend)
+    }
+
+    #[test]
+    fn backends_agree() {
+        let mut lockstep = Lockstep::new(
+            machine(Box::new(Interpreter)),
+            machine(Box::new(CachedInterpreter::new())),
+        );
+
+        let executed = lockstep.run(1000).unwrap();
+        assert!(lockstep.a.cpu.waitkey);
+        // 15 trips round the loop, 8 instructions of the last one, then Fx0A
+        assert_eq!(executed, 16 * 9);
+
+        lockstep.finish_waitkey(0xA);
+        assert_eq!(lockstep.a.cpu.v_reg[3], 0xA);
+        lockstep.run(1000).unwrap();
+        assert!(lockstep.a.cpu.fault.is_some());
+    }
+
+    #[test]
+    fn reports_divergence() {
+        let a = machine(Box::new(Interpreter));
+        let mut b = machine(Box::new(Interpreter));
+        b.cpu.quirks.stack = StackPolicy::Wrap;
+        let mut lockstep = Lockstep::new(a, b);
+
+        lockstep.run(1000).unwrap();
+        lockstep.finish_waitkey(0);
+        let divergence = lockstep.run(1000).unwrap_err();
+
+        // The 17th CALL overflows the stack, which only faults on one side
+        assert_eq!(divergence.pc, 0x214);
+        assert_eq!(divergence.step, 16 * 9 + 16);
+        assert_eq!(divergence.differences.len(), 2);
+        assert!(divergence.differences[0].starts_with("stack: [0x214, 0x214,"));
+        assert_eq!(
+            divergence.differences[1],
+            "fault: stack overflow at 0x214 != none"
+        );
+        assert!(divergence.context.contains("=> 0x214  2214  CALL 0x214\n"));
+    }
+
+    #[test]
+    fn compare_state() {
+        let a = CPU::new();
+        let mut b = a.clone();
+        assert!(compare(&a, &b).is_empty());
+
+        b.v_reg[0xC] = 0x12;
+        b.memory[0x300] = 1;
+        b.memory[0x301] = 1;
+        b.set_pixel(DISPLAY_WIDTH + 3, 1);
+        b.color_board.zones[5] = 3;
+        b.keypad2[0xA] = 1;
+        b.delay_wait = true;
+        assert_eq!(
+            compare(&a, &b),
+            vec![
+                "VC: 0x00 != 0x12",
+                "memory: 0x300: 0x00 != 0x01 (2 bytes differ)",
+                "display: (3, 1): 0 != 1 (1 pixels differ)",
+                "colour board zone 5: 1 != 3",
+                "keypad 2: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] != \
+                 [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]",
+                "delay wait: false != true",
+            ]
+        );
+    }
+}
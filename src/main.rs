@@ -1,101 +1,692 @@
 extern crate chip8;
 extern crate sdl2;
 
-use chip8::CPU;
-use chip8::Opcode;
+use chip8::analysis::ControlFlowGraph;
+use chip8::audio::{self, AudioStream, BeeperConfig};
+use chip8::backend;
+use chip8::coverage::Coverage;
+use chip8::filter::{self, Filter, Phosphor};
+use chip8::golden;
+use chip8::headless::{Headless, InputScript, CYCLES_PER_FRAME};
+use chip8::lockstep::{Lockstep, Machine};
+use chip8::palette::{self, Palette};
+use chip8::platform::Platform;
+use chip8::quirks::Quirks;
+use chip8::record::{self, Recorder};
+use chip8::scale::{self, Scaling};
+use chip8::screenshot;
+use chip8::suite::{Outcome, Suite};
+use chip8::timing::{FrameClock, Timing};
+use chip8::vip::Vip;
+use chip8::{CPU, MEMORY_SIZE, PROGRAM_ROM_START};
 
-use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum::RGB24;
+use sdl2::rect::Rect;
 use sdl2::render::TextureAccess;
+use sdl2::video::FullscreenType;
 
 use std::env;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+/// Command line options.
+struct Options {
+    rom: String,
+    /// JSON file to merge this run's coverage into.
+    coverage: Option<String>,
+    /// File to write an annotated disassembly of the covered ROM to.
+    listing: Option<String>,
+    quirks: Quirks,
+    /// Print self-modifying code events as they happen.
+    trace_smc: bool,
+    /// Name of the execution backend.
+    backend: String,
+    /// How many times bigger than 64x32 screenshots and recordings are.
+    screenshot_scale: usize,
+    /// Files to record to: GIFs, WAVs or PNG directories.
+    record: Vec<String>,
+    sound: BeeperConfig,
+    palette: Palette,
+    filter: Filter,
+    scaling: Scaling,
+    fullscreen: bool,
+    timing: Timing,
+    platform: Platform,
+    /// CHIP-8 interpreter image to run on an emulated COSMAC VIP.
+    vip: Option<String>,
+    /// Monitor ROM image for the VIP to boot through.
+    vip_monitor: Option<String>,
 }
 
-impl AudioCallback for SquareWave {
-    type Channel = f32;
+const USAGE: &str = "usage: chip8 [options] <rom>
+       chip8 cfg [--platform <name>] <rom> [<out.dot>]
+       chip8 lockstep [--platform <name>] <rom> <backend> <backend> [<steps>]
+       chip8 test-suite [--update] [--backend <name>] [--platform <name>] [<suite.txt>]
+       chip8 record [--input <script>] [--palette <palette>] [--screenshot-scale <n>]
+                    [--timing <timing>] [--platform <name>] [<sound options>]
+                    <rom> <frames> <out>...
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+options:
+  --coverage <file.json>  merge memory coverage into a JSON file
+  --listing <file>        write an annotated disassembly of the coverage
+  --profile <name>        start from a quirk profile: default, strict,
+                          lenient, vip
+  --stack <fault|wrap>    what to do on stack overflow/underflow
+  --memory <wrap|fault|warn>
+                          what to do on accesses past the end of memory
+  --sprite-x <wrap|clip>  what happens to sprites going off the left or right
+  --sprite-y <wrap|clip>  what happens to sprites going off the top or bottom
+  --display-wait          make Dxyn wait for the next frame, like the VIP
+  --sys <fault|ignore>    what to do when 0nnn calls machine code
+  --platform <name>       CHIP-8 variant: chip8, chip8e, chip8x, or hires for
+                          64x64 hi-res CHIP-8
+  --timing <timing>       instructions per frame (default 10), or vip to take
+                          as long as each one would on the COSMAC VIP
+  --vip <interpreter>     emulate a COSMAC VIP running this CHIP-8 interpreter
+                          image, so 0nnn runs 1802 machine code
+  --vip-monitor <rom>     boot the VIP through this monitor ROM image
+  --trace-smc             report self-modifying code
+  --backend <name>        how to execute instructions: interpreter, cached,
+                          or jit and jit-verify when built with --features jit
+  --palette <palette>     colours to draw in: classic, green, amber, lcd, octo,
+                          or 2 or 4 hex colours, e.g. 000000,33FF66
+  --filter <filter>       hide flicker: none, blend[:decay] (fading pixels),
+                          deflicker (OR of the last two frames), vip (CRT)
+  --scaler <name>         smooth the display: none, scale2x (or epx), scale3x
+  --scanlines             darken the gap between pixel rows like a CRT
+  --grid                  draw a grid between pixels like an LCD
+  --fullscreen            start in fullscreen
+  --screenshot-scale <n>  scale screenshots and recordings up n times (default 1)
+  --record <out>          record to an animated GIF, a WAV, or a directory of
+                          PNGs; can be given more than once
+
+sound options:
+  --tone <hz>             pitch of the beep (default 100)
+  --waveform <name>       square, sine, triangle or noise (default square)
+  --volume <0-1>          loudness of the beep (default 0.05)
+  --mute                  start muted
+
+keys:
+  F10                     fullscreen
+  F3                      next filter
+  F4                      next palette
+  F5                      next waveform
+  F6, F7                  volume down, up
+  F8                      mute
+  F11                     start or stop recording
+  F12                     screenshot";
+
+/// Options for the CHIP-8 emulation that don't mean anything when the real
+/// interpreter's running on an emulated VIP.
+const NOT_FOR_VIP: &[&str] = &[
+    "--coverage",
+    "--listing",
+    "--profile",
+    "--stack",
+    "--memory",
+    "--sprite-x",
+    "--sprite-y",
+    "--display-wait",
+    "--sys",
+    "--timing",
+    "--trace-smc",
+    "--backend",
+];
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut rom = None;
+    // The first of `NOT_FOR_VIP` given
+    let mut not_for_vip = None;
+    let mut options = Options {
+        rom: String::new(),
+        coverage: None,
+        listing: None,
+        quirks: Quirks::default(),
+        trace_smc: false,
+        backend: "interpreter".to_string(),
+        screenshot_scale: 1,
+        record: Vec::new(),
+        sound: BeeperConfig::default(),
+        palette: Palette::default(),
+        filter: Filter::None,
+        scaling: Scaling::default(),
+        fullscreen: false,
+        timing: Timing::default(),
+        platform: Platform::default(),
+        vip: None,
+        vip_monitor: None,
+    };
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        if not_for_vip.is_none() && NOT_FOR_VIP.contains(&arg.as_str()) {
+            not_for_vip = Some(arg.clone());
+        }
+        match arg.as_str() {
+            "--coverage" => options.coverage = Some(args.next().ok_or("--coverage needs a file")?),
+            "--listing" => options.listing = Some(args.next().ok_or("--listing needs a file")?),
+            "--profile" => {
+                options.quirks = Quirks::profile(&args.next().ok_or("--profile needs a name")?)?
+            }
+            "--stack" => {
+                options.quirks.stack = args.next().ok_or("--stack needs a policy")?.parse()?
+            }
+            "--memory" => {
+                options.quirks.memory = args.next().ok_or("--memory needs a policy")?.parse()?
+            }
+            "--sprite-x" => {
+                options.quirks.sprite_x = args.next().ok_or("--sprite-x needs a policy")?.parse()?
+            }
+            "--sprite-y" => {
+                options.quirks.sprite_y = args.next().ok_or("--sprite-y needs a policy")?.parse()?
+            }
+            "--display-wait" => options.quirks.display_wait = true,
+            "--sys" => options.quirks.sys = args.next().ok_or("--sys needs a policy")?.parse()?,
+            "--timing" => options.timing = args.next().ok_or("--timing needs a timing")?.parse()?,
+            "--platform" => {
+                options.platform = args.next().ok_or("--platform needs a name")?.parse()?
+            }
+            "--vip" => options.vip = Some(args.next().ok_or("--vip needs an interpreter image")?),
+            "--vip-monitor" => {
+                options.vip_monitor = Some(args.next().ok_or("--vip-monitor needs a ROM image")?)
+            }
+            "--trace-smc" => options.trace_smc = true,
+            "--filter" => options.filter = args.next().ok_or("--filter needs a name")?.parse()?,
+            "--scaler" => {
+                options.scaling.scaler = args.next().ok_or("--scaler needs a name")?.parse()?
+            }
+            "--scanlines" => options.scaling.scanlines = true,
+            "--grid" => options.scaling.grid = true,
+            "--fullscreen" => options.fullscreen = true,
+            "--palette" => {
+                options.palette = Palette::parse(&args.next().ok_or("--palette needs a palette")?)?
+            }
+            "--backend" => options.backend = args.next().ok_or("--backend needs a name")?,
+            "--screenshot-scale" => {
+                options.screenshot_scale = args
+                    .next()
+                    .ok_or("--screenshot-scale needs a number")?
+                    .parse()
+                    .ok()
+                    .filter(|&scale| scale > 0)
+                    .ok_or("--screenshot-scale needs a positive number")?
+            }
+            "--record" => options
+                .record
+                .push(args.next().ok_or("--record needs a file")?),
+            _ if parse_sound_option(&arg, &mut args, &mut options.sound)? => {}
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    options.rom = rom.ok_or("no ROM given")?;
+    if options.vip_monitor.is_some() && options.vip.is_none() {
+        return Err("--vip-monitor needs --vip".to_string());
+    }
+    if options.vip.is_some() && options.platform != Platform::Chip8 {
+        return Err("--vip only runs plain CHIP-8".to_string());
+    }
+    if let Some(option) = not_for_vip.filter(|_| options.vip.is_some()) {
+        return Err(format!("--vip can't be used with {}", option));
+    }
+    Ok(options)
+}
+
+/// Parse one of the sound options into `sound`, taking its value from
+/// `args`. Returns false if `arg` isn't a sound option.
+fn parse_sound_option<I: Iterator<Item = String>>(
+    arg: &str,
+    args: &mut I,
+    sound: &mut BeeperConfig,
+) -> Result<bool, String> {
+    match arg {
+        "--tone" => {
+            sound.tone = args
+                .next()
+                .and_then(|tone| tone.parse().ok())
+                .filter(|&tone: &f32| tone > 0.0 && tone < (audio::SAMPLE_RATE / 2) as f32)
+                .ok_or("--tone needs a frequency in Hz")?
+        }
+        "--waveform" => sound.waveform = args.next().ok_or("--waveform needs a name")?.parse()?,
+        "--volume" => {
+            sound.volume = args
+                .next()
+                .and_then(|volume| volume.parse().ok())
+                .filter(|&volume: &f32| (0.0..=1.0).contains(&volume))
+                .ok_or("--volume needs a number from 0 to 1")?
+        }
+        "--mute" => sound.muted = true,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Merge this run's coverage into any map already saved from earlier runs,
+/// then write out the JSON and/or disassembly listing.
+fn save_coverage(emulator: &CPU, options: &Options) -> Result<(), String> {
+    let mut coverage = emulator.coverage.clone().unwrap_or_default();
+
+    if let Some(ref path) = options.coverage {
+        if let Ok(json) = fs::read_to_string(path) {
+            coverage.merge(&Coverage::from_json(&json).map_err(|e| format!("{}: {}", path, e))?);
+        }
+        fs::write(path, coverage.to_json()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    if let Some(ref path) = options.listing {
+        // Skip the untouched zeroes after the end of the ROM
+        let start = emulator.platform.program_start();
+        let end = (start..MEMORY_SIZE)
+            .rev()
+            .find(|&addr| emulator.memory[addr] != 0 || coverage.flags(addr) != 0)
+            .map_or(start, |addr| addr + 1);
+        let listing = coverage.listing(&emulator.memory, start, end);
+        fs::write(path, listing).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// The first `chip8-N.png` in the current directory that doesn't exist yet.
+fn screenshot_path() -> PathBuf {
+    (1..)
+        .map(|n| PathBuf::from(format!("chip8-{}.png", n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// The first `chip8-N.gif` and `chip8-N.wav` pair that don't exist yet.
+fn recording_paths() -> (PathBuf, PathBuf) {
+    (1..)
+        .map(|n| {
+            (
+                PathBuf::from(format!("chip8-{}.gif", n)),
+                PathBuf::from(format!("chip8-{}.wav", n)),
+            )
+        })
+        .find(|(gif, wav)| !gif.exists() && !wav.exists())
+        .unwrap()
+}
+
+/// Start recording `cpu`'s display to each of `paths`.
+fn start_recording<P: AsRef<Path>>(
+    paths: &[P],
+    cpu: &CPU,
+    palette: &Palette,
+    scale: usize,
+    sound: &BeeperConfig,
+) -> Result<Vec<Box<dyn Recorder>>, String> {
+    paths
+        .iter()
+        .map(|path| record::create(path.as_ref(), palette, scale, cpu.platform, sound))
+        .collect()
+}
+
+/// Finish each of `recorders`, reporting any errors.
+fn finish_recording(recorders: &mut Vec<Box<dyn Recorder>>) {
+    for mut recorder in recorders.drain(..) {
+        if let Err(e) = recorder.finish() {
+            eprintln!("couldn't finish recording: {}", e);
         }
     }
 }
 
+/// The emulated COSMAC VIP for `--vip`, with the program already loaded
+/// into `cpu` copied over.
+fn start_vip(interpreter: &str, monitor: Option<&str>, cpu: &CPU) -> Result<Vip, String> {
+    let read = |path: &str| fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    let interpreter = read(interpreter)?;
+    let monitor = match monitor {
+        Some(path) => Some(read(path)?),
+        None => None,
+    };
+    let mut vip = Vip::new(&interpreter, monitor.as_deref())?;
+    vip.load_program(&cpu.memory[PROGRAM_ROM_START..]);
+    Ok(vip)
+}
+
+/// `chip8 record`: run a ROM headless for a number of frames, replaying an
+/// input script, and record it to one or more files.
+fn record_command(args: &[String]) -> Result<(), String> {
+    let mut input = InputScript::new();
+    let mut scale = 1;
+    let mut sound = BeeperConfig::default();
+    let mut palette = Palette::default();
+    let mut timing = Timing::default();
+    let mut platform = Platform::default();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => {
+                let path = args.next().ok_or("--input needs a file")?;
+                let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                input = InputScript::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            "--palette" => {
+                palette = Palette::parse(&args.next().ok_or("--palette needs a palette")?)?
+            }
+            "--screenshot-scale" => {
+                scale = args
+                    .next()
+                    .and_then(|scale| scale.parse().ok())
+                    .filter(|&scale| scale > 0)
+                    .ok_or("--screenshot-scale needs a positive number")?
+            }
+            "--timing" => timing = args.next().ok_or("--timing needs a timing")?.parse()?,
+            "--platform" => platform = args.next().ok_or("--platform needs a name")?.parse()?,
+            _ if parse_sound_option(&arg, &mut args, &mut sound)? => {}
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 3 {
+        return Err("record needs a ROM, a frame count and an output".to_string());
+    }
+
+    let rom = fs::read(&positional[0]).map_err(|e| format!("{}: {}", positional[0], e))?;
+    let frames: usize = positional[1]
+        .parse()
+        .map_err(|_| format!("bad frame count {:?}", positional[1]))?;
+    let mut cpu = CPU::new();
+    cpu.set_platform(platform);
+    cpu.seed_rng(0);
+    cpu.load_program(&rom);
+
+    let mut headless = Headless::new(cpu, backend::by_name("interpreter")?);
+    headless.input = input;
+    headless.clock = FrameClock::new(timing);
+    let mut recorders = start_recording(&positional[2..], &headless.cpu, &palette, scale, &sound)?;
+    while headless.frames < frames && headless.run_frame() {
+        for recorder in &mut recorders {
+            recorder.capture(&headless.cpu)?;
+        }
+    }
+    for recorder in &mut recorders {
+        recorder.finish()?;
+    }
+
+    if let Some(fault) = headless.cpu.fault {
+        eprintln!("stopped after {} frames: {}", headless.frames, fault);
+    }
+    Ok(())
+}
+
+/// `chip8 cfg`: write the control-flow graph of a ROM as Graphviz DOT, to a
+/// file or stdout.
+fn cfg_command(args: &[String]) -> Result<(), String> {
+    let mut platform = Platform::default();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => platform = args.next().ok_or("--platform needs a name")?.parse()?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let rom_path = positional.first().ok_or("no ROM given")?;
+    let rom = fs::read(rom_path).map_err(|e| format!("{}: {}", rom_path, e))?;
+    let graph = ControlFlowGraph::analyze(&rom, platform.program_start(), platform.entry_point());
+
+    for (start, end) in graph.unreachable_regions() {
+        eprintln!("unreachable: 0x{:03X}-0x{:03X}", start, end - 1);
+    }
+
+    match positional.get(1) {
+        Some(path) => fs::write(path, graph.to_dot()).map_err(|e| format!("{}: {}", path, e)),
+        None => {
+            print!("{}", graph.to_dot());
+            Ok(())
+        }
+    }
+}
+
+/// `chip8 lockstep`: run a ROM on two backends side by side and report the
+/// first instruction where they disagree. Key waits are answered with key 0,
+/// and a new frame starts every `CYCLES_PER_FRAME` instructions.
+fn lockstep_command(args: &[String]) -> Result<(), String> {
+    let mut platform = Platform::default();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => platform = args.next().ok_or("--platform needs a name")?.parse()?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 3 {
+        return Err("lockstep needs a ROM and two backends".to_string());
+    }
+
+    let rom = fs::read(&positional[0]).map_err(|e| format!("{}: {}", positional[0], e))?;
+    let steps = match positional.get(3) {
+        Some(steps) => steps
+            .parse()
+            .map_err(|_| format!("bad step count {:?}", steps))?,
+        None => 1_000_000,
+    };
+
+    let mut cpu = CPU::new();
+    cpu.set_platform(platform);
+    cpu.load_program(&rom);
+    cpu.seed_rng(0);
+    let mut lockstep = Lockstep::new(
+        Machine::new(cpu.clone(), backend::by_name(&positional[1])?),
+        Machine::new(cpu, backend::by_name(&positional[2])?),
+    );
+
+    while lockstep.steps < steps {
+        let frame = (steps - lockstep.steps).min(CYCLES_PER_FRAME);
+        if let Err(divergence) = lockstep.run(frame) {
+            println!("{}", divergence);
+            process::exit(1);
+        }
+        if lockstep.a.cpu.fault.is_some() {
+            break;
+        }
+        if lockstep.is_stopped() && lockstep.a.cpu.waitkey {
+            lockstep.finish_waitkey(0);
+        }
+        lockstep.vblank();
+    }
+
+    println!("no divergence in {} instructions", lockstep.steps);
+    Ok(())
+}
+
+/// `chip8 test-suite`: run the conformance ROMs in a suite manifest and
+/// compare their displays to the golden images. Exits with 1 if any fail.
+fn test_suite_command(args: &[String]) -> Result<(), String> {
+    let mut update = golden::update_mode();
+    let mut backend = "interpreter".to_string();
+    let mut manifest = "tests/roms/suite.txt".to_string();
+    let mut platform = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--update" => update = true,
+            "--backend" => backend = args.next().ok_or("--backend needs a name")?,
+            "--platform" => platform = Some(args.next().ok_or("--platform needs a name")?.parse()?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => manifest = arg,
+        }
+    }
+
+    let mut suite = Suite::load(Path::new(&manifest))?;
+    // Run every ROM on this platform, whatever the manifest says
+    if let Some(platform) = platform {
+        for case in &mut suite.cases {
+            case.platform = platform;
+        }
+    }
+    let results = suite.run(&backend, update)?;
+    for result in &results {
+        println!("{}", result);
+        if let Outcome::Fail(ref mismatch) = result.outcome {
+            println!("{}", mismatch);
+        }
+    }
+
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("cfg") {
+        if let Err(e) = cfg_command(&args[1..]) {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("test-suite") {
+        if let Err(e) = test_suite_command(&args[1..]) {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("record") {
+        if let Err(e) = record_command(&args[1..]) {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("lockstep") {
+        if let Err(e) = lockstep_command(&args[1..]) {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+        return;
+    }
+
+    let options = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+    let mut backend = backend::by_name(&options.backend).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
     let mut emulator = CPU::new();
+    emulator.quirks = options.quirks;
+    emulator.set_platform(options.platform);
+    let display_height = emulator.display_height();
+    let mut palette = options.palette;
+    // Where F4 has got to in the built-in palettes
+    let mut palette_index = None;
+    let mut phosphor = Phosphor::new(options.filter);
+    let mut recorders = start_recording(
+        &options.record,
+        &emulator,
+        &palette,
+        options.screenshot_scale,
+        &options.sound,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("couldn't start recording: {}", e);
+        process::exit(1);
+    });
+    // Recording started with F11, if there is one
+    let mut hotkey_recorders = Vec::new();
+    // Time left in the emulated frame
+    let mut clock = FrameClock::new(options.timing);
+    clock.start_frame();
 
-    emulator.load_rom(&args[1]);
+    emulator.load_rom(&options.rom);
+    if options.coverage.is_some() || options.listing.is_some() {
+        emulator.enable_coverage();
+    }
+    if options.trace_smc {
+        emulator.enable_smc_detection();
+    }
+    let mut vip = options.vip.as_ref().map(|interpreter| {
+        start_vip(interpreter, options.vip_monitor.as_deref(), &emulator).unwrap_or_else(|e| {
+            eprintln!("couldn't start the VIP: {}", e);
+            process::exit(1);
+        })
+    });
 
     // Initialize and SDL context and video subsystem
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
-    // Create/build our window. we start with a generous size but later
-    // we set the logical size to the proper amount
-    let window = video_subsystem
-        .window("CHIP-8 Emulator", 64 * 12, 32 * 12)
+    // Create/build our window. we start with a generous size, and the
+    // display is drawn at the biggest whole multiple that fits
+    let mut window = video_subsystem
+        .window("CHIP-8 Emulator", 64 * 12, display_height as u32 * 12)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
+    if options.fullscreen {
+        window.set_fullscreen(FullscreenType::Desktop).unwrap();
+    }
 
     // turn the window into a canvas?
-    let mut canvas = window.into_canvas()
+    let mut canvas = window
+        .into_canvas()
         // .present_vsync() // sync presents with refresh rate (60/122/144 hz)
         // .accelerated() // hardware acceleration
         .build()
         .unwrap();
 
-    canvas.set_logical_size(64, 32).unwrap();
-
     // let texture = canvas.create_texture();
     let texture_creator = canvas.texture_creator();
 
+    let factor = options.scaling.factor();
     let mut texture = texture_creator
         .create_texture(
             RGB24,
             TextureAccess::Streaming,
-            chip8::DISPLAY_WIDTH as u32,
-            chip8::DISPLAY_HEIGHT as u32,
+            (chip8::DISPLAY_WIDTH * factor) as u32,
+            (display_height * factor) as u32,
         )
         .unwrap();
 
-    // TODO cleanup audio code
     let audio_subsystem = sdl_context.audio().unwrap();
 
     let desired_spec = AudioSpecDesired {
-        freq: Some(44_100),
+        freq: Some(audio::SAMPLE_RATE as i32),
         channels: Some(1), // mono
         samples: None,     // default sample size
     };
 
-    let device = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| {
-            // Show obtained AudioSpec
-            println!("{:?}", spec);
+    // Samples are made as instructions run and queued, rather than played
+    // from a callback, so the beep lines up with emulated time
+    let queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+    let sample_rate = queue.spec().freq as usize;
+    let mut stream = AudioStream::new(options.sound, sample_rate, options.timing);
+    let queue_target = audio::QUEUE_FRAMES * sample_rate / record::FRAME_RATE;
+    queue.resume();
 
-            // initialize the audio callback
-            SquareWave {
-                phase_inc: 100.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.05,
-            }
-        })
-        .unwrap();
+    // Emulated time runs at the clock's units of time per 60 Hz frame
+    let cycle_time =
+        Duration::from_secs(1) / (options.timing.per_frame() * record::FRAME_RATE) as u32;
+    let mut next_cycle = Instant::now();
 
     // event pump... pumps out events I guess
     let mut event_pump = sdl_context.event_pump().unwrap();
@@ -108,9 +699,114 @@ fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'main_loop,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => {
+                    let path = screenshot_path();
+                    match screenshot::save(&emulator, &palette, options.screenshot_scale, &path) {
+                        Ok(()) => eprintln!("saved screenshot to {}", path.display()),
+                        Err(e) => eprintln!("couldn't save screenshot: {}", e),
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => {
+                    let next = match phosphor.filter {
+                        Filter::None => Filter::Blend {
+                            decay: filter::DECAY,
+                        },
+                        Filter::Blend { .. } => Filter::Deflicker,
+                        Filter::Deflicker => Filter::Vip,
+                        Filter::Vip => Filter::None,
+                    };
+                    phosphor = Phosphor::new(next);
+                    phosphor.update(&emulator);
+                    eprintln!("filter: {}", next);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => {
+                    let index = palette_index.map_or(0, |i| (i + 1) % palette::NAMES.len());
+                    palette = Palette::named(palette::NAMES[index]).unwrap();
+                    palette_index = Some(index);
+                    eprintln!("palette: {}", palette::NAMES[index]);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    let config = &mut stream.beeper.config;
+                    config.waveform = config.waveform.next();
+                    eprintln!("waveform: {}", config.waveform);
+                }
+                Event::KeyDown {
+                    keycode: Some(key @ Keycode::F6),
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(key @ Keycode::F7),
+                    ..
+                } => {
+                    let config = &mut stream.beeper.config;
+                    let step = if key == Keycode::F7 { 0.01 } else { -0.01 };
+                    config.volume = (config.volume + step).clamp(0.0, 1.0);
+                    eprintln!("volume: {:.2}", config.volume);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => {
+                    let config = &mut stream.beeper.config;
+                    config.muted = !config.muted;
+                    let state = if config.muted { "muted" } else { "unmuted" };
+                    eprintln!("{}", state);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off,
+                    };
+                    if let Err(e) = window.set_fullscreen(fullscreen) {
+                        eprintln!("couldn't change fullscreen: {}", e);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => {
+                    if hotkey_recorders.is_empty() {
+                        let (gif, wav) = recording_paths();
+                        let scale = options.screenshot_scale;
+                        let sound = stream.beeper.config;
+                        match start_recording(&[&gif, &wav], &emulator, &palette, scale, &sound) {
+                            Ok(started) => {
+                                eprintln!("recording to {} and {}", gif.display(), wav.display());
+                                hotkey_recorders = started;
+                            }
+                            Err(e) => eprintln!("couldn't start recording: {}", e),
+                        }
+                    } else {
+                        finish_recording(&mut hotkey_recorders);
+                        eprintln!("stopped recording");
+                    }
+                }
                 Event::KeyDown {
                     keycode: Some(key), ..
-                } => emulator.update_keypad(key, true),
+                } => {
+                    emulator.update_keypad(key, true);
+                    if emulator.waitkey {
+                        if let Some(hex) = emulator.keycode_to_hex(key) {
+                            emulator.finish_waitkey(hex);
+                        }
+                    }
+                }
                 Event::KeyUp {
                     keycode: Some(key), ..
                 } => emulator.update_keypad(key, false),
@@ -119,55 +815,91 @@ fn main() {
         }
 
         // clear screen
-        canvas.set_draw_color(Color::RGB(0, 0, 0)); // screen starts black
+        let [r, g, b] = palette.background();
+        canvas.set_draw_color(Color::RGB(r, g, b));
         canvas.clear();
 
-        emulator.emulate_cycle();
-
-        // waitkey
-        if emulator.waitkey {
-            'wait_loop: loop {
-                match event_pump.wait_event() {
-                    Event::Quit { .. } => break 'main_loop,
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => break 'main_loop,
-                    Event::KeyDown {
-                        keycode: Some(key), ..
-                    } => {
-                        match emulator.keycode_to_hex(key) {
-                            Some(hex) => {
-                                emulator.v_reg[emulator.opcode.x()] = hex;
-                                break 'wait_loop;
-                            },
-                            _ => (),
-                        }
-                    },
-                    _ => (),
+        let elapsed = if let Some(ref mut vip) = vip {
+            // The VIP runs a whole frame at a time, and shows it through
+            // the CPU's display and sound timer
+            vip.bus.keypad = emulator.keypad;
+            vip.run_frame();
+            vip.show(&mut emulator);
+            clock.idle()
+        } else if emulator.is_waiting() {
+            // Sit out the rest of the frame. Time goes on while Fx0A waits
+            // for a key, so the timers and the beeper keep running
+            clock.idle()
+        } else {
+            let batch = clock.batch(&emulator).min(1);
+            let executed = backend.run(&mut emulator, batch);
+            clock.ran(executed)
+        };
+
+        // play audio
+        let queued = queue.size() as usize / mem::size_of::<f32>();
+        stream.rate = audio::rate_for(queued, queue_target);
+        queue.queue(&stream.advance(emulator.sound_timer > 0, elapsed));
+
+        if clock.frame_over() {
+            clock.start_frame();
+            phosphor.update(&emulator);
+            for recorder in recorders.iter_mut().chain(&mut hotkey_recorders) {
+                if let Err(e) = recorder.capture(&emulator) {
+                    eprintln!("couldn't record frame: {}", e);
                 }
             }
-            emulator.waitkey = false;
+            // The timers tick between frames, after the frame's recorded
+            emulator.vblank();
         }
 
-        // play audio
-        if emulator.sound_timer > 0 {
-            device.resume();
-        } else {
-            device.pause();
+        if let Some(fault) = emulator.fault {
+            eprintln!("{}\n{}", fault, emulator.backtrace());
+            break 'main_loop;
+        }
+
+        if let Some(ref mut smc) = emulator.smc {
+            for event in smc.take_events() {
+                eprintln!("smc: {}", event);
+            }
         }
 
+        // Filters only change once a frame, from the display at its end
+        let rgb = match phosphor.filter {
+            Filter::None => palette.render(&emulator, 1),
+            _ => phosphor.render(&emulator, &palette, 1),
+        };
+        let rgb = options.scaling.apply(&rgb, display_height);
         texture
-            .update(None, &emulator.display, chip8::DISPLAY_WIDTH * 3)
+            .update(None, &rgb, chip8::DISPLAY_WIDTH * factor * 3)
             .unwrap();
 
-        // copy texture to renderer (canvas)
-        canvas.copy(&texture, None, None).unwrap();
+        // copy texture to renderer (canvas), with every pixel the same size
+        let (width, height) = canvas.output_size().unwrap();
+        let (x, y, width, height) = scale::fit(
+            chip8::DISPLAY_WIDTH,
+            display_height,
+            width as usize,
+            height as usize,
+        );
+        canvas
+            .copy(&texture, None, Rect::new(x, y, width, height))
+            .unwrap();
 
         // present
         canvas.present();
 
-        // TODO: sync at known pace. vsync is too fast
-        // thread::sleep(time::Duration::from_millis(10));
+        // Keep to emulated time
+        next_cycle += cycle_time * elapsed as u32;
+        let now = Instant::now();
+        if next_cycle > now {
+            thread::sleep(next_cycle - now);
+        }
+    }
+
+    finish_recording(&mut recorders);
+    finish_recording(&mut hotkey_recorders);
+    if let Err(e) = save_coverage(&emulator, &options) {
+        eprintln!("couldn't save coverage: {}", e);
     }
 }
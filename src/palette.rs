@@ -0,0 +1,235 @@
+//! Colours the display is drawn in.
+//!
+//! A palette has four colours, indexed by which bit planes a pixel is on
+//! in: 0 for neither, 1 for the first, 2 for the second and 3 for both. The
+//! plain CHIP-8 display only has the first plane, so only the first two
+//! colours are used until there are multi-plane modes. CHIP-8X programs
+//! choose their own colours, so they're drawn in those instead.
+
+use platform::Platform;
+use {CPU, DISPLAY_WIDTH};
+
+/// An RGB colour.
+pub type Rgb = [u8; 3];
+
+/// A colour `level` of the way from `from` to `to`.
+pub fn mix(from: Rgb, to: Rgb, level: f32) -> Rgb {
+    let level = level.clamp(0.0, 1.0);
+    let mut rgb = [0; 3];
+    for (i, channel) in rgb.iter_mut().enumerate() {
+        let (from, to) = (from[i] as f32, to[i] as f32);
+        *channel = (from + (to - from) * level).round() as u8;
+    }
+    rgb
+}
+
+/// Names of the built-in palettes, for `Palette::named`.
+pub const NAMES: &[&str] = &["classic", "green", "amber", "lcd", "octo"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    /// Colour of pixels that are off, on in the first plane, on in the
+    /// second, and on in both.
+    pub colors: [Rgb; 4],
+}
+
+impl Default for Palette {
+    /// White on black.
+    fn default() -> Palette {
+        Palette {
+            colors: [
+                [0x00, 0x00, 0x00],
+                [0xFF, 0xFF, 0xFF],
+                [0xAA, 0xAA, 0xAA],
+                [0x55, 0x55, 0x55],
+            ],
+        }
+    }
+}
+
+/// Parse a colour written as `RRGGBB`, with or without a leading `#`.
+fn parse_color(s: &str) -> Result<Rgb, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    let error = || format!("bad colour {:?} (expected RRGGBB)", s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(error());
+    }
+    let mut rgb = [0; 3];
+    for (i, channel) in rgb.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| error())?;
+    }
+    Ok(rgb)
+}
+
+impl Palette {
+    /// One of the built-in palettes in `NAMES`.
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    pub fn named(name: &str) -> Result<Palette, String> {
+        let colors = match name {
+            "classic" => return Ok(Palette::default()),
+            // Green phosphor monitor
+            "green" => [[0x0A, 0x14, 0x0A], [0x33, 0xFF, 0x66], [0x1F, 0x99, 0x3D], [0x14, 0x66, 0x29]],
+            // Amber phosphor monitor
+            "amber" => [[0x1A, 0x0F, 0x00], [0xFF, 0xB0, 0x00], [0xB3, 0x7B, 0x00], [0x66, 0x46, 0x00]],
+            // Greenish handheld LCD
+            "lcd" => [[0xC4, 0xCF, 0xA1], [0x1F, 0x24, 0x1A], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C]],
+            // Octo's defaults
+            "octo" => [[0x99, 0x66, 0x00], [0xFF, 0xCC, 0x00], [0xFF, 0x66, 0x00], [0x66, 0x22, 0x00]],
+            _ => return Err(format!("unknown palette {:?} ({})", name, NAMES.join(", "))),
+        };
+        Ok(Palette { colors })
+    }
+
+    /// A built-in palette, or custom colours as a comma-separated list of
+    /// two or four `RRGGBB` hex colours: background and foreground, then
+    /// optionally the second plane and both planes. With only two, the
+    /// foreground is used for the other planes too.
+    pub fn parse(s: &str) -> Result<Palette, String> {
+        if !s.contains(',') {
+            return Palette::named(s);
+        }
+
+        let colors = s
+            .split(',')
+            .map(|color| parse_color(color.trim()))
+            .collect::<Result<Vec<Rgb>, String>>()?;
+        match colors.len() {
+            2 => Ok(Palette {
+                colors: [colors[0], colors[1], colors[1], colors[1]],
+            }),
+            4 => Ok(Palette {
+                colors: [colors[0], colors[1], colors[2], colors[3]],
+            }),
+            _ => Err(format!("expected 2 or 4 colours, got {}", colors.len())),
+        }
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.colors[1]
+    }
+
+    /// The colour for a pixel, given which planes it's on in.
+    pub fn color(&self, planes: u8) -> Rgb {
+        self.colors[(planes & 3) as usize]
+    }
+
+    /// A colour `level` of the way from the background to the foreground,
+    /// for pixels that are partly lit.
+    pub fn mix(&self, level: f32) -> Rgb {
+        mix(self.colors[0], self.colors[1], level)
+    }
+
+    /// The display as RGB24, each pixel blown up to a `scale` x `scale`
+    /// square.
+    pub fn render(&self, cpu: &CPU, scale: usize) -> Vec<u8> {
+        let width = DISPLAY_WIDTH * scale;
+        let mut rgb = Vec::with_capacity(cpu.display_size() * scale * scale * 3);
+
+        for y in 0..cpu.display_height() * scale {
+            for x in 0..width {
+                let pixel = (y / scale) * DISPLAY_WIDTH + x / scale;
+                let on = cpu.get_pixel(pixel);
+                rgb.extend_from_slice(&match cpu.platform {
+                    Platform::Chip8X => cpu.color_board.color(pixel, on == 1),
+                    _ => self.color(on),
+                });
+            }
+        }
+        rgb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DISPLAY_SIZE;
+
+    #[test]
+    fn render() {
+        let mut cpu = CPU::new();
+        cpu.set_pixel(1, 1);
+        let palette = Palette {
+            colors: [[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]],
+        };
+
+        let rgb = palette.render(&cpu, 1);
+        assert_eq!(rgb.len(), DISPLAY_SIZE * 3);
+        assert_eq!(&rgb[..9], &[1, 2, 3, 4, 5, 6, 1, 2, 3]);
+
+        let rgb = palette.render(&cpu, 2);
+        assert_eq!(rgb.len(), DISPLAY_SIZE * 4 * 3);
+        // (2, 0) to (3, 1) come from the pixel that's on
+        let at = |x: usize, y: usize| &rgb[(y * DISPLAY_WIDTH * 2 + x) * 3..][..3];
+        assert_eq!(at(1, 1), &[1, 2, 3]);
+        assert_eq!(at(2, 0), &[4, 5, 6]);
+        assert_eq!(at(3, 1), &[4, 5, 6]);
+        assert_eq!(at(4, 1), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn default_matches_display_buffer() {
+        let mut cpu = CPU::new();
+        cpu.set_pixel(100, 1);
+        assert_eq!(
+            Palette::default().render(&cpu, 1),
+            cpu.display[..DISPLAY_SIZE * 3].to_vec()
+        );
+    }
+
+    #[test]
+    fn planes() {
+        let palette = Palette::named("octo").unwrap();
+        assert_eq!(palette.color(0), [0x99, 0x66, 0x00]);
+        assert_eq!(palette.color(2), [0xFF, 0x66, 0x00]);
+        assert_eq!(palette.color(3), palette.colors[3]);
+        assert_eq!(palette.foreground(), palette.color(1));
+    }
+
+    #[test]
+    fn mix() {
+        let palette = Palette::parse("000000,FF8040").unwrap();
+        assert_eq!(palette.mix(0.0), [0, 0, 0]);
+        assert_eq!(palette.mix(0.5), [0x80, 0x40, 0x20]);
+        assert_eq!(palette.mix(1.0), [0xFF, 0x80, 0x40]);
+        assert_eq!(palette.mix(2.0), palette.mix(1.0));
+    }
+
+    #[test]
+    fn named() {
+        for name in NAMES {
+            assert!(Palette::named(name).is_ok(), "{}", name);
+        }
+        assert_eq!(Palette::named("classic"), Ok(Palette::default()));
+        assert!(Palette::named("purple").is_err());
+    }
+
+    #[test]
+    fn custom() {
+        assert_eq!(
+            Palette::parse("#000000,ffffff"),
+            Ok(Palette {
+                colors: [[0, 0, 0], [0xFF; 3], [0xFF; 3], [0xFF; 3]],
+            })
+        );
+        assert_eq!(
+            Palette::parse("102030, 405060, 708090, A0B0C0")
+                .unwrap()
+                .colors,
+            [
+                [0x10, 0x20, 0x30],
+                [0x40, 0x50, 0x60],
+                [0x70, 0x80, 0x90],
+                [0xA0, 0xB0, 0xC0]
+            ]
+        );
+        assert_eq!(Palette::parse("amber"), Palette::named("amber"));
+        assert!(Palette::parse("000000,ffffff,777777").is_err());
+        assert!(Palette::parse("000000,fffff").is_err());
+        assert!(Palette::parse("000000,gggggg").is_err());
+        assert!(Palette::parse("000000,ÿÿÿ").is_err());
+    }
+}
@@ -0,0 +1,615 @@
+//! CHIP-8 variants, each with its own extra instructions, memory layout and
+//! display size.
+//!
+//! - CHIP-8E fills gaps in the instruction set with more skips, loads and
+//!   stores of a range of registers, relative jumps and I/O ports.
+//! - CHIP-8X drives the VP-590 colour board and the VP-580 second keypad.
+//!   Its interpreter is bigger, so programs start at 0x300.
+//! - Hi-res CHIP-8 shows two pages of display memory as a 64x64 display.
+//!   Programs start with `1260`, which the hi-res interpreter takes as a
+//!   jump to where they really start, at 0x2C0.
+//!
+//! A variant's own instructions are looked up before the standard ones, so
+//! everything else works the same as on plain CHIP-8.
+
+use std::fmt;
+use std::str::FromStr;
+
+use palette::Rgb;
+use PROGRAM_ROM_START;
+use {Opcode, CPU, DISPLAY_HEIGHT, DISPLAY_WIDTH, MAX_DISPLAY_HEIGHT, MEMORY_SIZE};
+
+/// Names of the platforms, for `--platform`.
+pub const NAMES: &[&str] = &["chip8", "chip8e", "chip8x", "hires"];
+
+/// Where CHIP-8X programs are loaded, after its bigger interpreter.
+pub const CHIP8X_PROGRAM_START: usize = 0x300;
+/// Where hi-res programs really start, past the `1260` at 0x200.
+pub const HIRES_PROGRAM_START: usize = 0x2C0;
+
+/// Columns of CHIP-8X colour zones. Each zone is 8 pixels wide and one tall.
+const ZONE_COLUMNS: usize = DISPLAY_WIDTH / 8;
+/// Number of CHIP-8X colour zones.
+pub const ZONES: usize = ZONE_COLUMNS * DISPLAY_HEIGHT;
+
+/// The VP-590's eight colours, by number.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub const VP590_COLORS: [Rgb; 8] = [
+    [0x00, 0x00, 0x00], // black
+    [0xFF, 0x00, 0x00], // red
+    [0x00, 0x00, 0xFF], // blue
+    [0xFF, 0x00, 0xFF], // violet
+    [0x00, 0xFF, 0x00], // green
+    [0xFF, 0xFF, 0x00], // yellow
+    [0x00, 0xFF, 0xFF], // aqua
+    [0xFF, 0xFF, 0xFF], // white
+];
+
+/// Background colours `02A0` steps through, starting from blue.
+const BACKGROUNDS: [u8; 4] = [2, 0, 4, 1];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Platform {
+    /// The original COSMAC VIP interpreter.
+    #[default]
+    Chip8,
+    Chip8E,
+    Chip8X,
+    /// Two-page 64x64 hi-res CHIP-8.
+    HiRes,
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        match s {
+            "chip8" => Ok(Platform::Chip8),
+            "chip8e" => Ok(Platform::Chip8E),
+            "chip8x" => Ok(Platform::Chip8X),
+            "hires" => Ok(Platform::HiRes),
+            _ => Err(format!("unknown platform {:?} ({})", s, NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Platform::Chip8 => "chip8",
+            Platform::Chip8E => "chip8e",
+            Platform::Chip8X => "chip8x",
+            Platform::HiRes => "hires",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Platform {
+    /// Where programs are loaded and start running.
+    pub fn program_start(&self) -> usize {
+        match *self {
+            Platform::Chip8X => CHIP8X_PROGRAM_START,
+            _ => PROGRAM_ROM_START,
+        }
+    }
+
+    /// Where programs really start: past the `1260` on hi-res, otherwise
+    /// where they're loaded.
+    pub fn entry_point(&self) -> usize {
+        match *self {
+            Platform::HiRes => HIRES_PROGRAM_START,
+            _ => self.program_start(),
+        }
+    }
+
+    /// Rows of pixels in the display. It's 64 pixels wide on all of them.
+    pub fn display_height(&self) -> usize {
+        match *self {
+            Platform::HiRes => MAX_DISPLAY_HEIGHT,
+            _ => DISPLAY_HEIGHT,
+        }
+    }
+}
+
+/// The CHIP-8X colour board: a background colour, and a foreground colour
+/// for each zone of the display.
+#[derive(Clone, Debug)]
+pub struct ColorBoard {
+    /// Which of `BACKGROUNDS` the background is.
+    background: usize,
+    /// Foreground colour of each zone, row by row.
+    pub zones: [u8; ZONES],
+}
+
+impl ColorBoard {
+    /// Red on blue, as the board starts up.
+    pub fn new() -> ColorBoard {
+        ColorBoard {
+            background: 0,
+            zones: [1; ZONES],
+        }
+    }
+
+    /// The number of the background colour.
+    pub fn background(&self) -> u8 {
+        BACKGROUNDS[self.background]
+    }
+
+    /// Colour the zone at `column` and pixel row `row`, wrapping around the
+    /// display.
+    fn set(&mut self, column: usize, row: usize, color: u8) {
+        self.zones[(row % DISPLAY_HEIGHT) * ZONE_COLUMNS + column % ZONE_COLUMNS] = color & 7;
+    }
+
+    /// The number of the colour a pixel of the display is, on or off.
+    pub fn color_number(&self, pixel: usize, on: bool) -> u8 {
+        if on {
+            self.zones[(pixel / DISPLAY_WIDTH) * ZONE_COLUMNS + (pixel % DISPLAY_WIDTH) / 8]
+        } else {
+            self.background()
+        }
+    }
+
+    /// How a pixel of the display looks, on or off.
+    pub fn color(&self, pixel: usize, on: bool) -> Rgb {
+        VP590_COLORS[self.color_number(pixel, on) as usize]
+    }
+}
+
+impl Default for ColorBoard {
+    fn default() -> ColorBoard {
+        ColorBoard::new()
+    }
+}
+
+/// Look up the function that executes an opcode that's different on
+/// `platform`, or None if it's the same as on plain CHIP-8.
+pub fn decode(platform: Platform, opcode: u16) -> Option<fn(&mut CPU)> {
+    match platform {
+        Platform::Chip8 => None,
+        Platform::Chip8E => decode_chip8e(opcode),
+        Platform::Chip8X => decode_chip8x(opcode),
+        Platform::HiRes => decode_hires(opcode),
+    }
+}
+
+fn decode_chip8e(opcode: u16) -> Option<fn(&mut CPU)> {
+    let handler: fn(&mut CPU) = match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00ED => stop,
+            0x00F2 => nop,
+            0x0151 => wait_delay,
+            0x0188 => skip,
+            _ => return None,
+        },
+        0x5000 => match opcode & 0xF00F {
+            0x5001 => skip_greater,
+            0x5002 => store_range,
+            0x5003 => load_range,
+            _ => return None,
+        },
+        0xB000 => match opcode & 0xFF00 {
+            0xBB00 => jump_back,
+            0xBF00 => jump_forward,
+            _ => return None,
+        },
+        0xF000 => match opcode & 0xF0FF {
+            0xF003 => output,
+            0xF01B => skip_bytes,
+            0xF04F => set_delay_and_wait,
+            0xF0E3 | 0xF0E7 => input,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(handler)
+}
+
+fn decode_chip8x(opcode: u16) -> Option<fn(&mut CPU)> {
+    let handler: fn(&mut CPU) = match opcode & 0xF000 {
+        0x0000 if opcode == 0x02A0 => next_background,
+        0x5000 if opcode & 0xF00F == 0x5001 => add_nibbles,
+        0xB000 if opcode.n() == 0 => color_zones,
+        0xB000 => color_rows,
+        0xE000 => match opcode & 0xF0FF {
+            0xE0F2 => skip_key2,
+            0xE0F5 => skip_not_key2,
+            _ => return None,
+        },
+        0xF000 => match opcode & 0xF0FF {
+            0xF0F8 => output,
+            0xF0FB => input,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(handler)
+}
+
+fn decode_hires(opcode: u16) -> Option<fn(&mut CPU)> {
+    let handler: fn(&mut CPU) = match opcode {
+        0x0230 => CPU::opcode_cls,
+        0x1260 => hires_start,
+        _ => return None,
+    };
+    Some(handler)
+}
+
+/// (CHIP-8E 00ED) Stop, by never moving on.
+fn stop(_cpu: &mut CPU) {}
+
+/// (CHIP-8E 00F2) Do nothing.
+fn nop(cpu: &mut CPU) {
+    cpu.pc += 2;
+}
+
+/// (CHIP-8E 0151) Wait for the delay timer to run out.
+fn wait_delay(cpu: &mut CPU) {
+    if cpu.delay_timer == 0 {
+        cpu.delay_wait = false;
+        cpu.pc += 2;
+    }
+}
+
+/// (CHIP-8E 0188) Skip the next instruction.
+fn skip(cpu: &mut CPU) {
+    cpu.pc += 4;
+}
+
+/// (CHIP-8E 5xy1) Skip the next instruction if Vx > Vy.
+fn skip_greater(cpu: &mut CPU) {
+    if cpu.v_reg[cpu.opcode.x()] > cpu.v_reg[cpu.opcode.y()] {
+        cpu.pc += 2;
+    }
+    cpu.pc += 2;
+}
+
+/// (CHIP-8E 5xy2) Store [Vx..Vy] at I, and move I past them.
+fn store_range(cpu: &mut CPU) {
+    let (x, y) = (cpu.opcode.x(), cpu.opcode.y());
+    let len = (y + 1).saturating_sub(x);
+
+    if !cpu.check_access(cpu.i_addr, len) {
+        return;
+    }
+    for i in 0..len {
+        let value = cpu.v_reg[x + i];
+        cpu.write_memory(cpu.i_addr + i, value);
+    }
+    cpu.i_addr += len;
+    cpu.pc += 2;
+}
+
+/// (CHIP-8E 5xy3) Fill [Vx..Vy] from I, and move I past them.
+fn load_range(cpu: &mut CPU) {
+    let (x, y) = (cpu.opcode.x(), cpu.opcode.y());
+    let len = (y + 1).saturating_sub(x);
+
+    if !cpu.check_access(cpu.i_addr, len) {
+        return;
+    }
+    for i in 0..len {
+        cpu.v_reg[x + i] = cpu.read_memory(cpu.i_addr + i, ::coverage::READ);
+    }
+    cpu.i_addr += len;
+    cpu.pc += 2;
+}
+
+/// (CHIP-8E BBnn) Jump back nn bytes from the next instruction.
+fn jump_back(cpu: &mut CPU) {
+    cpu.pc = (cpu.pc + 2 + MEMORY_SIZE - cpu.opcode.kk() as usize) % MEMORY_SIZE;
+}
+
+/// (CHIP-8E BFnn) Jump forward nn bytes from the next instruction.
+fn jump_forward(cpu: &mut CPU) {
+    cpu.pc += 2 + cpu.opcode.kk() as usize;
+}
+
+/// (CHIP-8E Fx03, CHIP-8X FxF8) Send Vx to an output port. Nothing's
+/// plugged into them.
+fn output(cpu: &mut CPU) {
+    cpu.pc += 2;
+}
+
+/// (CHIP-8E FxE3 and FxE7, CHIP-8X FxFB) Read an input port into Vx.
+/// Nothing's plugged into them, so they read 0.
+fn input(cpu: &mut CPU) {
+    cpu.v_reg[cpu.opcode.x()] = 0;
+    cpu.pc += 2;
+}
+
+/// (CHIP-8E Fx1B) Skip Vx bytes.
+fn skip_bytes(cpu: &mut CPU) {
+    cpu.pc += 2 + cpu.v_reg[cpu.opcode.x()] as usize;
+}
+
+/// (CHIP-8E Fx4F) Set the delay timer to Vx, then wait for it to run out.
+fn set_delay_and_wait(cpu: &mut CPU) {
+    if !cpu.delay_wait {
+        cpu.delay_timer = cpu.v_reg[cpu.opcode.x()];
+        cpu.delay_wait = true;
+    }
+    wait_delay(cpu);
+}
+
+/// (CHIP-8X 02A0) Step the background to the next colour.
+fn next_background(cpu: &mut CPU) {
+    let board = &mut cpu.color_board;
+    board.background = (board.background + 1) % BACKGROUNDS.len();
+    cpu.pc += 2;
+}
+
+/// (CHIP-8X 5xy1) Add each nibble of Vy to the same nibble of Vx, keeping
+/// the bottom three bits of each.
+fn add_nibbles(cpu: &mut CPU) {
+    let (x, y) = (cpu.opcode.x(), cpu.opcode.y());
+    cpu.v_reg[x] = ((cpu.v_reg[x] & 0x77) + (cpu.v_reg[y] & 0x77)) & 0x77;
+    cpu.pc += 2;
+}
+
+/// (CHIP-8X BxY0) Colour an area of 8x4 pixel zones with VY. The low
+/// nibble of Vx is the leftmost column and the high nibble how many more
+/// there are to the right; V(x+1) is the same for rows.
+fn color_zones(cpu: &mut CPU) {
+    let x = cpu.opcode.x();
+    let across = cpu.v_reg[x] as usize;
+    let down = cpu.v_reg[(x + 1) & 0xF] as usize;
+    let color = cpu.v_reg[cpu.opcode.y()];
+
+    for column in (across & 0xF)..=(across & 0xF) + (across >> 4) {
+        for row in (down & 0xF) * 4..((down & 0xF) + (down >> 4) + 1) * 4 {
+            cpu.color_board.set(column, row, color);
+        }
+    }
+    cpu.pc += 2;
+}
+
+/// (CHIP-8X BxyN) Colour N rows of 8x1 pixel zones with Vy, starting at the
+/// pixel at (Vx, V(x+1)).
+fn color_rows(cpu: &mut CPU) {
+    let x = cpu.opcode.x();
+    let column = cpu.v_reg[x] as usize / 8;
+    let top = cpu.v_reg[(x + 1) & 0xF] as usize;
+    let color = cpu.v_reg[cpu.opcode.y()];
+
+    for row in top..top + cpu.opcode.n() {
+        cpu.color_board.set(column, row, color);
+    }
+    cpu.pc += 2;
+}
+
+/// (CHIP-8X ExF2) Skip the next instruction if key Vx on the second keypad
+/// is pressed.
+fn skip_key2(cpu: &mut CPU) {
+    if cpu.keypad2[(cpu.v_reg[cpu.opcode.x()] & 0xF) as usize] == 1 {
+        cpu.pc += 2;
+    }
+    cpu.pc += 2;
+}
+
+/// (CHIP-8X ExF5) Skip the next instruction if key Vx on the second keypad
+/// isn't pressed.
+fn skip_not_key2(cpu: &mut CPU) {
+    if cpu.keypad2[(cpu.v_reg[cpu.opcode.x()] & 0xF) as usize] == 0 {
+        cpu.pc += 2;
+    }
+    cpu.pc += 2;
+}
+
+/// (Hi-res 1260) Jump to 0x260, except at the start of a program, where it
+/// jumps to the program's real start instead.
+fn hires_start(cpu: &mut CPU) {
+    cpu.pc = if cpu.pc == PROGRAM_ROM_START {
+        HIRES_PROGRAM_START
+    } else {
+        cpu.opcode.nnn()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::cpu_with;
+
+    #[test]
+    fn names() {
+        for name in NAMES {
+            let platform: Platform = name.parse().unwrap();
+            assert_eq!(platform.to_string(), *name);
+        }
+        assert!("schip".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn layout() {
+        let cpu = cpu_with(Platform::Chip8X, &[0x12, 0x34]);
+        assert_eq!(cpu.pc, 0x300);
+        assert_eq!(cpu.memory[0x300], 0x12);
+        assert_eq!(cpu.display_height(), 32);
+
+        let cpu = cpu_with(Platform::HiRes, &[]);
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.display_height(), 64);
+    }
+
+    #[test]
+    fn same_opcode_per_platform() {
+        // 5xy1 is a skip on CHIP-8E, an add on CHIP-8X, and 5xy0 otherwise
+        let program = [0x60, 0x25, 0x61, 0x13, 0x50, 0x11];
+        let mut cpu = cpu_with(Platform::Chip8E, &program);
+        for _ in 0..3 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.pc, 0x208);
+
+        let mut cpu = cpu_with(Platform::Chip8X, &program);
+        for _ in 0..3 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.v_reg[0], 0x30);
+
+        let mut cpu = cpu_with(Platform::Chip8, &program);
+        for _ in 0..3 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn chip8e_ranges() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let program = [
+            0x61, 0x11, 0x62, 0x22, 0x63, 0x33, // V1..V3 = 11, 22, 33
+            0xA3, 0x00,                         // LD I, 0x300
+            0x51, 0x32,                         // store V1..V3
+            0xA3, 0x01,                         // LD I, 0x301
+            0x54, 0x53,                         // load V4..V5
+        ];
+        let mut cpu = cpu_with(Platform::Chip8E, &program);
+        for _ in 0..5 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.memory[0x300..0x303], [0x11, 0x22, 0x33]);
+        assert_eq!(cpu.i_addr, 0x303);
+
+        for _ in 0..2 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.v_reg[4..6], [0x22, 0x33]);
+        assert_eq!(cpu.i_addr, 0x303);
+    }
+
+    #[test]
+    fn chip8e_jumps() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let program = [
+            0xBF, 0x02, // 200: forward to 0x204
+            0x00, 0x00, // 202
+            0x01, 0x88, // 204: skip to 0x208
+            0x00, 0x00, // 206
+            0x60, 0x04, // 208: LD V0, 4
+            0xF0, 0x1B, // 20A: skip 4 bytes to 0x210
+            0x00, 0x00, // 20C
+            0x00, 0x00, // 20E
+            0xBB, 0x0A, // 210: back to 0x208
+        ];
+        let mut cpu = cpu_with(Platform::Chip8E, &program);
+        let mut trace = Vec::new();
+        for _ in 0..6 {
+            cpu.emulate_cycle();
+            trace.push(cpu.pc);
+        }
+        assert_eq!(trace, vec![0x204, 0x208, 0x20A, 0x210, 0x208, 0x20A]);
+        assert!(cpu.fault.is_none());
+    }
+
+    #[test]
+    fn chip8e_delay_wait() {
+        // LD V0, 3; set DT and wait; stop
+        let program = [0x60, 0x03, 0xF0, 0x4F, 0x00, 0xED];
+        let mut cpu = cpu_with(Platform::Chip8E, &program);
+        cpu.emulate_cycle();
+        cpu.emulate_cycle();
+        assert_eq!(cpu.pc, 0x202);
+        assert!(cpu.delay_wait);
+
+        // The timer counts down once a frame, not once an instruction
+        for _ in 0..10 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.pc, 0x202);
+        for _ in 0..3 {
+            cpu.vblank();
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.pc, 0x204);
+        assert!(!cpu.delay_wait);
+
+        cpu.emulate_cycle();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn chip8x_colors() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let program = [
+            0x60, 0x11, // LD V0, 0x11: columns 1 and 2
+            0x61, 0x02, // LD V1, 0x02: zone row 2
+            0x62, 0x04, // LD V2, 4 (green)
+            0xB0, 0x20, // colour the zones
+            0x02, 0xA0, // next background
+        ];
+        let mut cpu = cpu_with(Platform::Chip8X, &program);
+        for _ in 0..5 {
+            cpu.emulate_cycle();
+        }
+
+        let board = &cpu.color_board;
+        let green = VP590_COLORS[4];
+        assert_eq!(board.color(8 * DISPLAY_WIDTH + 8, true), green);
+        assert_eq!(board.color(11 * DISPLAY_WIDTH + 23, true), green);
+        assert_eq!(board.color(12 * DISPLAY_WIDTH + 8, true), VP590_COLORS[1]);
+        assert_eq!(board.color(8 * DISPLAY_WIDTH + 24, true), VP590_COLORS[1]);
+        // Blue, then black
+        assert_eq!(board.color(0, false), VP590_COLORS[0]);
+    }
+
+    #[test]
+    fn chip8x_color_rows() {
+        // Two rows from (20, 5) in violet
+        let program = [0x60, 0x14, 0x61, 0x05, 0x62, 0x03, 0xB0, 0x22];
+        let mut cpu = cpu_with(Platform::Chip8X, &program);
+        for _ in 0..4 {
+            cpu.emulate_cycle();
+        }
+
+        let zone = |row: usize| cpu.color_board.zones[row * ZONE_COLUMNS + 2];
+        assert_eq!((zone(4), zone(5), zone(6), zone(7)), (1, 3, 3, 1));
+    }
+
+    #[test]
+    fn chip8x_second_keypad() {
+        // LD V0, 7; skip if key 7 on the second keypad is down
+        let program = [0x60, 0x07, 0xE0, 0xF2];
+        let mut cpu = cpu_with(Platform::Chip8X, &program);
+        cpu.keypad[7] = 1;
+        cpu.emulate_cycle();
+        cpu.emulate_cycle();
+        assert_eq!(cpu.pc, 0x304);
+
+        let mut cpu = cpu_with(Platform::Chip8X, &program);
+        cpu.keypad2[7] = 1;
+        cpu.emulate_cycle();
+        cpu.emulate_cycle();
+        assert_eq!(cpu.pc, 0x306);
+    }
+
+    #[test]
+    fn hires() {
+        let mut program = vec![0x12, 0x60];
+        program.resize(HIRES_PROGRAM_START - PROGRAM_ROM_START, 0);
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        program.extend_from_slice(&[
+            0x60, 0x00, // LD V0, 0
+            0xF0, 0x29, // LD F, V0
+            0x61, 0x28, // LD V1, 40
+            0xD1, 0x15, // DRW V1, V1, 5
+            0x02, 0x30, // clear the screen
+        ]);
+        let mut cpu = cpu_with(Platform::HiRes, &program);
+        cpu.emulate_cycle();
+        assert_eq!(cpu.pc, HIRES_PROGRAM_START);
+
+        // Row 40 is on the display, rather than wrapping to row 8
+        for _ in 0..4 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.get_pixel(40 * DISPLAY_WIDTH + 40), 1);
+        assert_eq!(cpu.get_pixel(8 * DISPLAY_WIDTH + 40), 0);
+
+        cpu.emulate_cycle();
+        assert_eq!(cpu.get_pixel(40 * DISPLAY_WIDTH + 40), 0);
+        assert!(cpu.fault.is_none());
+    }
+}
@@ -0,0 +1,168 @@
+//! Configurable behaviour for things interpreters disagree on, or that badly
+//! behaved ROMs rely on.
+
+use std::str::FromStr;
+
+/// What to do when a ROM calls too deep or returns with an empty stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackPolicy {
+    /// Stop with a `Fault`.
+    Fault,
+    /// Let the stack pointer wrap around, overwriting the oldest entry, like
+    /// interpreters that don't check it.
+    Wrap,
+}
+
+impl FromStr for StackPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StackPolicy, String> {
+        match s {
+            "fault" => Ok(StackPolicy::Fault),
+            "wrap" => Ok(StackPolicy::Wrap),
+            _ => Err(format!("unknown stack policy {:?} (fault, wrap)", s)),
+        }
+    }
+}
+
+/// What to do when an instruction reads or writes past the end of memory,
+/// e.g. `Fx55` with I near 0xFFF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    /// Wrap the address around at 4K, like the original interpreter.
+    Wrap,
+    /// Stop with a `Fault`.
+    Fault,
+    /// Print a warning to stderr, then wrap.
+    Warn,
+}
+
+impl FromStr for MemoryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MemoryPolicy, String> {
+        match s {
+            "wrap" => Ok(MemoryPolicy::Wrap),
+            "fault" => Ok(MemoryPolicy::Fault),
+            "warn" => Ok(MemoryPolicy::Warn),
+            _ => Err(format!("unknown memory policy {:?} (wrap, fault, warn)", s)),
+        }
+    }
+}
+
+/// What happens to the part of a sprite that goes off an edge of the
+/// display. Either way, a sprite that starts off the display wraps around
+/// to start on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgePolicy {
+    /// Draw it on the other side.
+    Wrap,
+    /// Don't draw it, like the original COSMAC VIP interpreter.
+    Clip,
+}
+
+impl FromStr for EdgePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EdgePolicy, String> {
+        match s {
+            "wrap" => Ok(EdgePolicy::Wrap),
+            "clip" => Ok(EdgePolicy::Clip),
+            _ => Err(format!("unknown edge policy {:?} (wrap, clip)", s)),
+        }
+    }
+}
+
+/// What to do with `0nnn`, which calls a machine code routine on the
+/// original hardware, when there's no host routine registered for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SysPolicy {
+    /// Skip it, like most modern interpreters.
+    Ignore,
+    /// Stop with a `Fault`.
+    Fault,
+}
+
+impl FromStr for SysPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SysPolicy, String> {
+        match s {
+            "ignore" => Ok(SysPolicy::Ignore),
+            "fault" => Ok(SysPolicy::Fault),
+            _ => Err(format!("unknown sys policy {:?} (ignore, fault)", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub stack: StackPolicy,
+    pub memory: MemoryPolicy,
+    /// Make `Dxyn` wait for the next frame once it's drawn, like the COSMAC
+    /// VIP waiting for the vertical blank, so there's at most one draw a
+    /// frame.
+    pub display_wait: bool,
+    /// Sprites going off the left or right edge.
+    pub sprite_x: EdgePolicy,
+    /// Sprites going off the top or bottom edge.
+    pub sprite_y: EdgePolicy,
+    pub sys: SysPolicy,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            stack: StackPolicy::Fault,
+            memory: MemoryPolicy::Wrap,
+            display_wait: false,
+            sprite_x: EdgePolicy::Wrap,
+            sprite_y: EdgePolicy::Wrap,
+            sys: SysPolicy::Fault,
+        }
+    }
+}
+
+/// Names of the built-in quirk profiles.
+pub const PROFILES: &[&str] = &["default", "strict", "lenient", "vip"];
+
+impl Quirks {
+    /// Look up a built-in profile: `default`, `strict` (fault on anything
+    /// questionable), `lenient` (wrap everything around, never fault) or
+    /// `vip` (clip sprites and wait for the frame to draw, like the COSMAC
+    /// VIP interpreter).
+    pub fn profile(name: &str) -> Result<Quirks, String> {
+        match name {
+            "default" => Ok(Quirks::default()),
+            "strict" => Ok(Quirks {
+                stack: StackPolicy::Fault,
+                memory: MemoryPolicy::Fault,
+                display_wait: false,
+                sprite_x: EdgePolicy::Wrap,
+                sprite_y: EdgePolicy::Wrap,
+                sys: SysPolicy::Fault,
+            }),
+            "lenient" => Ok(Quirks {
+                stack: StackPolicy::Wrap,
+                memory: MemoryPolicy::Wrap,
+                display_wait: false,
+                sprite_x: EdgePolicy::Wrap,
+                sprite_y: EdgePolicy::Wrap,
+                sys: SysPolicy::Ignore,
+            }),
+            "vip" => Ok(Quirks {
+                stack: StackPolicy::Fault,
+                memory: MemoryPolicy::Wrap,
+                display_wait: true,
+                sprite_x: EdgePolicy::Clip,
+                sprite_y: EdgePolicy::Clip,
+                sys: SysPolicy::Fault,
+            }),
+            _ => Err(format!(
+                "unknown quirk profile {:?} ({})",
+                name,
+                PROFILES.join(", ")
+            )),
+        }
+    }
+}
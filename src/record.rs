@@ -0,0 +1,406 @@
+//! Recording gameplay, one image per emulated 60 Hz frame.
+//!
+//! A run of identical frames is only stored once: an animated GIF shows it
+//! for longer, and a PNG sequence skips the repeats, naming each file after
+//! the frame it was captured on so the timing can be rebuilt. Audio is
+//! recorded to a WAV file a frame at a time, from the sound timer, so it
+//! lines up with the video.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use gif;
+
+use audio::{self, Beeper, BeeperConfig, WavWriter};
+use palette::Palette;
+use platform::{Platform, VP590_COLORS};
+use screenshot;
+use {CPU, DISPLAY_WIDTH};
+
+/// Frames per second of emulated time.
+pub const FRAME_RATE: usize = 60;
+
+pub trait Recorder {
+    /// Capture the display at the end of an emulated frame.
+    fn capture(&mut self, cpu: &CPU) -> Result<(), String>;
+
+    /// Write out anything still buffered and finish the file. Nothing more
+    /// can be captured afterwards.
+    fn finish(&mut self) -> Result<(), String>;
+}
+
+/// Which pixels are on, to spot repeated frames. On CHIP-8X it's the number
+/// of the colour each pixel is instead, so colour changes count too.
+fn pixels(cpu: &CPU) -> Vec<u8> {
+    (0..cpu.display_size())
+        .map(|pixel| {
+            let on = cpu.get_pixel(pixel);
+            match cpu.platform {
+                Platform::Chip8X => cpu.color_board.color_number(pixel, on == 1),
+                _ => on,
+            }
+        })
+        .collect()
+}
+
+/// Records an animated GIF in the palette's colours, or on CHIP-8X the
+/// colour board's.
+pub struct GifRecorder<W: Write> {
+    encoder: Option<gif::Encoder<W>>,
+    scale: usize,
+    /// Rows in the display being recorded.
+    height: usize,
+    /// The frame waiting to be written, and how many frames it's been on
+    /// screen for.
+    pending: Option<(Vec<u8>, usize)>,
+    /// Frames written so far, counting repeats.
+    frames: usize,
+}
+
+impl<W: Write> GifRecorder<W> {
+    /// Record the display of a `platform`.
+    pub fn new(
+        out: W,
+        palette: &Palette,
+        scale: usize,
+        platform: Platform,
+    ) -> Result<GifRecorder<W>, String> {
+        let colors: Vec<u8> = match platform {
+            Platform::Chip8X => VP590_COLORS.iter().flat_map(|rgb| rgb.to_vec()).collect(),
+            _ => palette.colors.iter().flat_map(|rgb| rgb.to_vec()).collect(),
+        };
+        let height = platform.display_height();
+
+        let (image_width, image_height) = ((DISPLAY_WIDTH * scale) as u16, (height * scale) as u16);
+        let mut encoder = gif::Encoder::new(out, image_width, image_height, &colors)
+            .map_err(|e| e.to_string())?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| e.to_string())?;
+
+        Ok(GifRecorder {
+            encoder: Some(encoder),
+            scale,
+            height,
+            pending: None,
+            frames: 0,
+        })
+    }
+
+    /// Write the pending frame. GIF delays are in hundredths of a second,
+    /// so they're worked out from the running total to avoid drift. A delay
+    /// too long for one GIF frame is split over several of the same image.
+    fn flush(&mut self) -> Result<(), String> {
+        let (pixels, count) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let centiseconds = |frames: usize| (frames * 100 + FRAME_RATE / 2) / FRAME_RATE;
+        let delay = centiseconds(self.frames + count) - centiseconds(self.frames);
+        self.frames += count;
+
+        let scale = self.scale;
+        let width = DISPLAY_WIDTH * scale;
+        let buffer: Vec<u8> = (0..width * self.height * scale)
+            .map(|i| pixels[(i / width / scale) * DISPLAY_WIDTH + (i % width) / scale])
+            .collect();
+        let mut frame = gif::Frame {
+            width: width as u16,
+            height: (self.height * scale) as u16,
+            buffer: buffer.into(),
+            ..gif::Frame::default()
+        };
+
+        let encoder = self.encoder.as_mut().ok_or("recording already finished")?;
+        let mut delay = delay;
+        loop {
+            frame.delay = delay.min(u16::MAX as usize) as u16;
+            encoder.write_frame(&frame).map_err(|e| e.to_string())?;
+            delay -= frame.delay as usize;
+            if delay == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Recorder for GifRecorder<W> {
+    fn capture(&mut self, cpu: &CPU) -> Result<(), String> {
+        let pixels = pixels(cpu);
+        if let Some((ref last, ref mut count)) = self.pending {
+            if *last == pixels {
+                *count += 1;
+                return Ok(());
+            }
+        }
+        self.flush()?;
+        self.pending = Some((pixels, 1));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.flush()?;
+        if let Some(encoder) = self.encoder.take() {
+            encoder
+                .into_inner()
+                .map_err(|e| e.to_string())?
+                .flush()
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Records a directory of PNGs, `frame-000001.png` and so on, skipping
+/// frames that are the same as the one before.
+pub struct PngSequence {
+    dir: PathBuf,
+    palette: Palette,
+    scale: usize,
+    last: Option<Vec<u8>>,
+    /// Frames captured so far, counting repeats.
+    frames: usize,
+}
+
+impl PngSequence {
+    pub fn new(dir: &Path, palette: &Palette, scale: usize) -> Result<PngSequence, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        Ok(PngSequence {
+            dir: dir.to_path_buf(),
+            palette: *palette,
+            scale,
+            last: None,
+            frames: 0,
+        })
+    }
+}
+
+impl Recorder for PngSequence {
+    fn capture(&mut self, cpu: &CPU) -> Result<(), String> {
+        self.frames += 1;
+        let pixels = pixels(cpu);
+        if self.last.as_ref() == Some(&pixels) {
+            return Ok(());
+        }
+        self.last = Some(pixels);
+
+        let path = self.dir.join(format!("frame-{:06}.png", self.frames));
+        screenshot::save(cpu, &self.palette, self.scale, &path)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Records the beeper to a WAV file.
+pub struct WavRecorder<W: Write + Seek> {
+    writer: Option<WavWriter<W>>,
+    beeper: Beeper,
+}
+
+impl<W: Write + Seek> WavRecorder<W> {
+    pub fn new(out: W, sound: &BeeperConfig) -> Result<WavRecorder<W>, String> {
+        Ok(WavRecorder {
+            writer: Some(WavWriter::new(out, audio::SAMPLE_RATE).map_err(|e| e.to_string())?),
+            beeper: Beeper::new(*sound, audio::SAMPLE_RATE),
+        })
+    }
+}
+
+impl<W: Write + Seek> Recorder for WavRecorder<W> {
+    fn capture(&mut self, cpu: &CPU) -> Result<(), String> {
+        let samples = self.beeper.frame(cpu.sound_timer > 0);
+        let writer = self.writer.as_mut().ok_or("recording already finished")?;
+        writer.write(&samples).map_err(|e| e.to_string())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Start recording to `path`: an animated GIF if it ends in `.gif`, audio
+/// if it ends in `.wav`, otherwise a directory of PNGs, recording the
+/// display of a `platform`.
+pub fn create(
+    path: &Path,
+    palette: &Palette,
+    scale: usize,
+    platform: Platform,
+    sound: &BeeperConfig,
+) -> Result<Box<dyn Recorder>, String> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let create_file = || File::create(path).map_err(|e| format!("{}: {}", path.display(), e));
+    match extension.as_deref() {
+        Some("gif") => Ok(Box::new(GifRecorder::new(
+            BufWriter::new(create_file()?),
+            palette,
+            scale,
+            platform,
+        )?)),
+        Some("wav") => Ok(Box::new(WavRecorder::new(
+            BufWriter::new(create_file()?),
+            sound,
+        )?)),
+        _ => Ok(Box::new(PngSequence::new(path, palette, scale)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use DISPLAY_HEIGHT;
+
+    /// Decode a GIF into (delay, pixel indices) per frame.
+    fn decode(data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(data).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn gif_dedupes_frames() {
+        let mut out = Vec::new();
+        {
+            let mut recorder =
+                GifRecorder::new(&mut out, &Palette::default(), 1, Platform::Chip8).unwrap();
+            let mut cpu = CPU::new();
+            for _ in 0..3 {
+                recorder.capture(&cpu).unwrap();
+            }
+            cpu.set_pixel(DISPLAY_WIDTH + 1, 1);
+            for _ in 0..60 {
+                recorder.capture(&cpu).unwrap();
+            }
+            recorder.finish().unwrap();
+        }
+
+        let frames = decode(&out);
+        assert_eq!(frames.len(), 2);
+        // 3 frames is 5 hundredths of a second, 63 frames is 105
+        assert_eq!(frames[0].0, 5);
+        assert_eq!(frames[1].0, 100);
+        assert!(frames[0].1.iter().all(|&index| index == 0));
+        assert_eq!(frames[1].1[DISPLAY_WIDTH + 1], 1);
+        assert_eq!(frames[1].1.iter().filter(|&&index| index == 1).count(), 1);
+    }
+
+    #[test]
+    fn gif_long_delay() {
+        let mut out = Vec::new();
+        {
+            let mut recorder =
+                GifRecorder::new(&mut out, &Palette::default(), 1, Platform::Chip8).unwrap();
+            let cpu = CPU::new();
+            for _ in 0..40_000 {
+                recorder.capture(&cpu).unwrap();
+            }
+            recorder.finish().unwrap();
+        }
+
+        let frames = decode(&out);
+        let delays: Vec<u16> = frames.iter().map(|frame| frame.0).collect();
+        // 40,000 frames is 66,667 hundredths of a second
+        assert_eq!(delays, vec![u16::MAX, 1132]);
+        assert_eq!(frames[0].1, frames[1].1);
+    }
+
+    #[test]
+    fn gif_scaled() {
+        let mut out = Vec::new();
+        {
+            let mut recorder =
+                GifRecorder::new(&mut out, &Palette::default(), 2, Platform::Chip8).unwrap();
+            let mut cpu = CPU::new();
+            cpu.set_pixel(1, 1);
+            recorder.capture(&cpu).unwrap();
+            recorder.finish().unwrap();
+        }
+
+        let frames = decode(&out);
+        let width = DISPLAY_WIDTH * 2;
+        assert_eq!(frames[0].1.len(), width * DISPLAY_HEIGHT * 2);
+        assert_eq!(&frames[0].1[..5], &[0, 0, 1, 1, 0]);
+        assert_eq!(&frames[0].1[width..width + 5], &[0, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn gif_color_board() {
+        let mut out = Vec::new();
+        let mut cpu = CPU::new();
+        cpu.set_platform(Platform::Chip8X);
+        {
+            let mut recorder =
+                GifRecorder::new(&mut out, &Palette::default(), 1, Platform::Chip8X).unwrap();
+            cpu.set_pixel(1, 1);
+            recorder.capture(&cpu).unwrap();
+            // Recolouring the zone is a new frame
+            cpu.color_board.zones[0] = 4;
+            recorder.capture(&cpu).unwrap();
+            recorder.finish().unwrap();
+        }
+
+        let frames = decode(&out);
+        assert_eq!(frames.len(), 2);
+        let background = cpu.color_board.background();
+        assert_eq!(&frames[0].1[..3], &[background, 1, background]);
+        assert_eq!(&frames[1].1[..3], &[background, 4, background]);
+    }
+
+    #[test]
+    fn wav_follows_sound_timer() {
+        let mut out = std::io::Cursor::new(Vec::new());
+        {
+            let mut recorder = WavRecorder::new(&mut out, &BeeperConfig::default()).unwrap();
+            let mut cpu = CPU::new();
+            recorder.capture(&cpu).unwrap();
+            cpu.sound_timer = 2;
+            recorder.capture(&cpu).unwrap();
+            recorder.finish().unwrap();
+        }
+
+        let data = out.into_inner();
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(samples.len(), audio::SAMPLES_PER_FRAME * 2);
+        let (silent, beep) = samples.split_at(audio::SAMPLES_PER_FRAME);
+        assert!(silent.iter().all(|&sample| sample == 0));
+        assert!(beep.iter().all(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn png_sequence() {
+        let dir = env::temp_dir().join(format!("chip8-record-{}", std::process::id()));
+        let mut recorder = PngSequence::new(&dir, &Palette::default(), 1).unwrap();
+        let mut cpu = CPU::new();
+        recorder.capture(&cpu).unwrap();
+        recorder.capture(&cpu).unwrap();
+        cpu.set_pixel(0, 1);
+        recorder.capture(&cpu).unwrap();
+        recorder.finish().unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, vec!["frame-000001.png", "frame-000003.png"]);
+    }
+}
@@ -0,0 +1,320 @@
+//! Scaling the display up for the window, on the CPU.
+//!
+//! All of these work on RGB24 images like the ones `Palette::render` makes.
+//! Scale2x (also called EPX) and Scale3x round off the staircase edges of
+//! diagonal lines without blurring; scanlines and the pixel grid darken
+//! the edge of each emulated pixel so they look like a CRT or an LCD. The
+//! window then shows the result at the biggest whole multiple that fits, so
+//! every emulated pixel comes out the same size.
+
+use std::str::FromStr;
+
+use DISPLAY_WIDTH;
+
+/// How much scanlines and the grid darken the pixels under them, out of 256.
+const OVERLAY_BRIGHTNESS: u16 = 96;
+/// How many times overlays scale the image up when there's no scaler, so
+/// there's room for them inside each pixel.
+const OVERLAY_SCALE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scaler {
+    /// Leave the image alone, for the window to scale with square pixels.
+    None,
+    Scale2x,
+    Scale3x,
+}
+
+impl FromStr for Scaler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scaler, String> {
+        match s {
+            "none" => Ok(Scaler::None),
+            "scale2x" | "epx" => Ok(Scaler::Scale2x),
+            "scale3x" => Ok(Scaler::Scale3x),
+            _ => Err(format!("unknown scaler {:?} (none, scale2x, scale3x)", s)),
+        }
+    }
+}
+
+/// Everything done to the display before it goes to the window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scaling {
+    pub scaler: Scaler,
+    /// Darken the bottom row of each pixel.
+    pub scanlines: bool,
+    /// Darken the bottom row and right column of each pixel.
+    pub grid: bool,
+}
+
+impl Default for Scaling {
+    fn default() -> Scaling {
+        Scaling {
+            scaler: Scaler::None,
+            scanlines: false,
+            grid: false,
+        }
+    }
+}
+
+impl Scaling {
+    /// How many times bigger `apply` makes the image.
+    pub fn factor(&self) -> usize {
+        match self.scaler {
+            Scaler::None if self.scanlines || self.grid => OVERLAY_SCALE,
+            Scaler::None => 1,
+            Scaler::Scale2x => 2,
+            Scaler::Scale3x => 3,
+        }
+    }
+
+    /// Scale up the display as RGB24, `height` pixels tall.
+    pub fn apply(&self, rgb: &[u8], height: usize) -> Vec<u8> {
+        let width = DISPLAY_WIDTH;
+        let factor = self.factor();
+        let mut out = match self.scaler {
+            Scaler::None => nearest(rgb, width, height, factor),
+            Scaler::Scale2x => scale2x(rgb, width, height),
+            Scaler::Scale3x => scale3x(rgb, width, height),
+        };
+        if self.scanlines || self.grid {
+            overlay(&mut out, width * factor, factor, self.grid);
+        }
+        out
+    }
+}
+
+type Rgb = [u8; 3];
+
+/// The pixel at (x, y), with coordinates off the edge clamped to it.
+fn at(rgb: &[u8], width: usize, height: usize, x: isize, y: isize) -> Rgb {
+    let x = x.clamp(0, width as isize - 1) as usize;
+    let y = y.clamp(0, height as isize - 1) as usize;
+    let i = (y * width + x) * 3;
+    [rgb[i], rgb[i + 1], rgb[i + 2]]
+}
+
+/// Scale up an image by `n` with each pixel blown up to an `n` x `n` square.
+pub fn nearest(rgb: &[u8], width: usize, height: usize, n: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rgb.len() * n * n);
+    for y in 0..height * n {
+        for x in 0..width * n {
+            let i = ((y / n) * width + x / n) * 3;
+            out.extend_from_slice(&rgb[i..i + 3]);
+        }
+    }
+    out
+}
+
+/// Write an `n` x `n` block of pixels into an image `width` pixels wide.
+fn put_block(out: &mut [u8], width: usize, x: usize, y: usize, n: usize, block: &[Rgb]) {
+    for (i, pixel) in block.iter().enumerate() {
+        let offset = ((y * n + i / n) * width + x * n + i % n) * 3;
+        out[offset..offset + 3].copy_from_slice(pixel);
+    }
+}
+
+/// Scale up an image 2 times with Scale2x.
+pub fn scale2x(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut out = vec![0; rgb.len() * 4];
+    for y in 0..height {
+        for x in 0..width {
+            let (x, y) = (x as isize, y as isize);
+            let get = |dx, dy| at(rgb, width, height, x + dx, y + dy);
+            let (p, a, b, c, d) = (get(0, 0), get(0, -1), get(1, 0), get(-1, 0), get(0, 1));
+
+            let block = [
+                if c == a && c != d && a != b { a } else { p },
+                if a == b && a != c && b != d { b } else { p },
+                if d == c && d != b && c != a { c } else { p },
+                if b == d && b != a && d != c { d } else { p },
+            ];
+            put_block(&mut out, width * 2, x as usize, y as usize, 2, &block);
+        }
+    }
+    out
+}
+
+/// Scale up an image 3 times with Scale3x.
+pub fn scale3x(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut out = vec![0; rgb.len() * 9];
+    for y in 0..height {
+        for x in 0..width {
+            let (x, y) = (x as isize, y as isize);
+            let get = |dx, dy| at(rgb, width, height, x + dx, y + dy);
+            #[cfg_attr(rustfmt, rustfmt_skip)]
+            let (a, b, c, d, e, f, g, h, i) = (
+                get(-1, -1), get(0, -1), get(1, -1),
+                get(-1, 0), get(0, 0), get(1, 0),
+                get(-1, 1), get(0, 1), get(1, 1),
+            );
+
+            #[cfg_attr(rustfmt, rustfmt_skip)]
+            let block = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            put_block(&mut out, width * 3, x as usize, y as usize, 3, &block);
+        }
+    }
+    out
+}
+
+/// Darken the bottom row of each `cell` x `cell` block of an image `width`
+/// pixels wide, and the right column too if `grid`.
+pub fn overlay(rgb: &mut [u8], width: usize, cell: usize, grid: bool) {
+    if cell < 2 {
+        return;
+    }
+    for (index, pixel) in rgb.chunks_mut(3).enumerate() {
+        let (x, y) = (index % width, index / width);
+        if y % cell == cell - 1 || (grid && x % cell == cell - 1) {
+            for channel in pixel {
+                *channel = (*channel as u16 * OVERLAY_BRIGHTNESS / 256) as u8;
+            }
+        }
+    }
+}
+
+/// Where to draw an image `width` x `height` in a window `out_width` x
+/// `out_height`: the biggest whole multiple of its size that fits, in the
+/// middle. Returns (x, y, width, height). If the window is too small for
+/// even one times, the image is squeezed to fit, keeping its shape.
+pub fn fit(
+    width: usize,
+    height: usize,
+    out_width: usize,
+    out_height: usize,
+) -> (i32, i32, u32, u32) {
+    let n = (out_width / width).min(out_height / height);
+    let (w, h) = if n > 0 {
+        (width * n, height * n)
+    } else if out_width * height < out_height * width {
+        (out_width, height * out_width / width)
+    } else {
+        (width * out_height / height, out_height)
+    };
+    (
+        ((out_width - w) / 2) as i32,
+        ((out_height - h) / 2) as i32,
+        w as u32,
+        h as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DISPLAY_HEIGHT;
+
+    const W: Rgb = [0xFF; 3];
+    const K: Rgb = [0x00; 3];
+
+    fn image(pixels: &[Rgb]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| p.to_vec()).collect()
+    }
+
+    #[test]
+    fn nearest_scaling() {
+        let out = nearest(&image(&[W, K]), 2, 1, 2);
+        assert_eq!(out, image(&[W, W, K, K, W, W, K, K]));
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        // A diagonal line: the outside corners are cut and the steps
+        // between the pixels filled in
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rgb = image(&[
+            W, K,
+            K, W,
+        ]);
+        let out = scale2x(&rgb, 2, 2);
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let expected = image(&[
+            W, W, K, K,
+            W, K, W, K,
+            K, W, K, W,
+            K, K, W, W,
+        ]);
+        assert_eq!(out, expected);
+
+        // Flat areas stay flat
+        assert_eq!(scale2x(&image(&[K; 4]), 2, 2), image(&[K; 16]));
+    }
+
+    #[test]
+    fn scale3x_rounds_diagonals() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let rgb = image(&[
+            W, K,
+            K, W,
+        ]);
+        let out = scale3x(&rgb, 2, 2);
+        // The corners where the line passes get filled in
+        let pixel = |x: usize, y: usize| &out[(y * 6 + x) * 3..][..3];
+        assert_eq!(pixel(0, 0), &W);
+        assert_eq!(pixel(3, 2), &W);
+        assert_eq!(pixel(2, 3), &W);
+        assert_eq!(pixel(5, 0), &K);
+        assert_eq!(pixel(0, 5), &K);
+        assert_eq!(scale3x(&image(&[K; 4]), 2, 2), image(&[K; 36]));
+    }
+
+    #[test]
+    fn overlays() {
+        let mut rgb = image(&[W; 4]);
+        overlay(&mut rgb, 2, 2, false);
+        assert_eq!(rgb[..6], [0xFF; 6]);
+        assert_eq!(rgb[6], (0xFF * OVERLAY_BRIGHTNESS / 256) as u8);
+
+        let mut rgb = image(&[W; 4]);
+        overlay(&mut rgb, 2, 2, true);
+        assert_eq!(rgb[..3], [0xFF; 3]);
+        assert!(rgb[3..].iter().all(|&c| c < 0xFF));
+    }
+
+    #[test]
+    fn scaling() {
+        let rgb = vec![0xFF; DISPLAY_WIDTH * DISPLAY_HEIGHT * 3];
+        let scaling = Scaling::default();
+        assert_eq!(scaling.apply(&rgb, DISPLAY_HEIGHT), rgb);
+
+        let scaling = Scaling {
+            scanlines: true,
+            ..Scaling::default()
+        };
+        assert_eq!(scaling.factor(), OVERLAY_SCALE);
+        assert_eq!(scaling.apply(&rgb, DISPLAY_HEIGHT).len(), rgb.len() * 16);
+
+        let scaling = Scaling {
+            scaler: Scaler::Scale3x,
+            grid: true,
+            ..Scaling::default()
+        };
+        assert_eq!(scaling.apply(&rgb, DISPLAY_HEIGHT).len(), rgb.len() * 9);
+        assert_eq!("epx".parse(), Ok(Scaler::Scale2x));
+        assert!("hq2x".parse::<Scaler>().is_err());
+    }
+
+    #[test]
+    fn integer_fit() {
+        assert_eq!(fit(64, 32, 768, 384), (0, 0, 768, 384));
+        // Letterboxed, at 10 times
+        assert_eq!(fit(64, 32, 700, 400), (30, 40, 640, 320));
+        // Too small: squeezed
+        assert_eq!(fit(64, 32, 32, 100), (0, 42, 32, 16));
+    }
+}
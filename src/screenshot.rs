@@ -0,0 +1,189 @@
+//! Saving the display as an image.
+//!
+//! PNG and PPM are written in the palette's colours. PBM only has black and
+//! white, so pixels that are on are written as black ink, whatever the
+//! palette.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use png;
+
+use palette::Palette;
+use {CPU, DISPLAY_WIDTH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    /// Netpbm bitmap (P4).
+    Pbm,
+    /// Netpbm pixmap (P6).
+    Ppm,
+}
+
+impl Format {
+    /// Pick a format from a file's extension.
+    pub fn from_path(path: &Path) -> Result<Format, String> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("png") => Ok(Format::Png),
+            Some("pbm") => Ok(Format::Pbm),
+            Some("ppm") => Ok(Format::Ppm),
+            _ => Err(format!(
+                "{}: unknown image format (png, pbm, ppm)",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Write the display as a PNG, scaled up `scale` times.
+pub fn write_png<W: Write>(out: W, cpu: &CPU, palette: &Palette, scale: usize) -> io::Result<()> {
+    let width = (DISPLAY_WIDTH * scale) as u32;
+    let height = (cpu.display_height() * scale) as u32;
+
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&palette.render(cpu, scale))?;
+    Ok(())
+}
+
+/// Write the display as a binary PPM, scaled up `scale` times.
+pub fn write_ppm<W: Write>(
+    mut out: W,
+    cpu: &CPU,
+    palette: &Palette,
+    scale: usize,
+) -> io::Result<()> {
+    write!(
+        out,
+        "P6\n{} {}\n255\n",
+        DISPLAY_WIDTH * scale,
+        cpu.display_height() * scale
+    )?;
+    out.write_all(&palette.render(cpu, scale))
+}
+
+/// Write the display as a binary PBM, scaled up `scale` times.
+pub fn write_pbm<W: Write>(mut out: W, cpu: &CPU, scale: usize) -> io::Result<()> {
+    let width = DISPLAY_WIDTH * scale;
+    let height = cpu.display_height() * scale;
+    write!(out, "P4\n{} {}\n", width, height)?;
+
+    // Rows are packed 8 pixels to a byte, most significant bit first
+    let mut row = vec![0; width.div_ceil(8)];
+    for y in 0..height {
+        for byte in row.iter_mut() {
+            *byte = 0;
+        }
+        for x in 0..width {
+            if cpu.get_pixel((y / scale) * DISPLAY_WIDTH + x / scale) == 1 {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        out.write_all(&row)?;
+    }
+    Ok(())
+}
+
+/// Save the display to `path`, in the format its extension names.
+pub fn save(cpu: &CPU, palette: &Palette, scale: usize, path: &Path) -> Result<(), String> {
+    let format = Format::from_path(path)?;
+    let error = |e: io::Error| format!("{}: {}", path.display(), e);
+    let mut out = BufWriter::new(File::create(path).map_err(error)?);
+
+    match format {
+        Format::Png => write_png(&mut out, cpu, palette, scale),
+        Format::Pbm => write_pbm(&mut out, cpu, scale),
+        Format::Ppm => write_ppm(&mut out, cpu, palette, scale),
+    }
+    .and_then(|_| out.flush())
+    .map_err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_pixel(0, 1);
+        cpu.set_pixel(DISPLAY_WIDTH + 9, 1);
+        cpu
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(Format::from_path(Path::new("a.PNG")), Ok(Format::Png));
+        assert_eq!(Format::from_path(Path::new("a/b.pbm")), Ok(Format::Pbm));
+        assert_eq!(Format::from_path(Path::new("c.ppm")), Ok(Format::Ppm));
+        assert!(Format::from_path(Path::new("d.gif")).is_err());
+        assert!(Format::from_path(Path::new("e")).is_err());
+    }
+
+    #[test]
+    fn pbm() {
+        let mut out = Vec::new();
+        write_pbm(&mut out, &cpu(), 1).unwrap();
+
+        let header = b"P4\n64 32\n";
+        assert_eq!(&out[..header.len()], header);
+        let rows = &out[header.len()..];
+        assert_eq!(rows.len(), 8 * 32);
+        assert_eq!(&rows[..10], &[0x80, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn scaled_pbm() {
+        let mut out = Vec::new();
+        write_pbm(&mut out, &cpu(), 3).unwrap();
+
+        let header = b"P4\n192 96\n";
+        assert_eq!(&out[..header.len()], header);
+        let rows = &out[header.len()..];
+        assert_eq!(rows.len(), 24 * 96);
+        // The first three rows start with three pixels on
+        assert_eq!(rows[0], 0xE0);
+        assert_eq!(rows[24 * 2], 0xE0);
+        assert_eq!(rows[24 * 3], 0x00);
+    }
+
+    #[test]
+    fn ppm() {
+        let mut out = Vec::new();
+        let palette = Palette::parse("102030,A0B0C0").unwrap();
+        write_ppm(&mut out, &cpu(), &palette, 1).unwrap();
+
+        let header = b"P6\n64 32\n255\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(
+            &out[header.len()..header.len() + 6],
+            &[0xA0, 0xB0, 0xC0, 0x10, 0x20, 0x30]
+        );
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut out = Vec::new();
+        let palette = Palette::default();
+        write_png(&mut out, &cpu(), &palette, 2).unwrap();
+
+        let decoder = png::Decoder::new(&out[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(
+            &pixels[..info.buffer_size()],
+            &palette.render(&cpu(), 2)[..]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Self-modifying code detection.
+//!
+//! Keeps track of which addresses have been executed and which have been
+//! written by the program, and reports writes to code that has already run
+//! as well as execution of bytes the program wrote itself.
+
+use std::fmt;
+
+use coverage::{Coverage, EXECUTED, WRITTEN};
+use MEMORY_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmcEvent {
+    /// The instruction at `pc` wrote to `addr`, which had already been
+    /// executed.
+    CodeWritten { pc: usize, addr: usize },
+    /// The byte at `addr` was executed as part of the instruction at `pc`
+    /// after the program had written to it.
+    WrittenExecuted { pc: usize, addr: usize },
+}
+
+impl fmt::Display for SmcEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SmcEvent::CodeWritten { pc, addr } => write!(
+                f,
+                "0x{:03X} wrote to already executed code at 0x{:03X}",
+                pc, addr
+            ),
+            SmcEvent::WrittenExecuted { pc, addr } => {
+                write!(f, "0x{:03X} executed modified code at 0x{:03X}", pc, addr)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SmcDetector {
+    /// Which addresses have been executed and written so far.
+    access: Coverage,
+    /// Which kinds of event have already been reported for each address, so
+    /// a loop patching the same instruction doesn't flood the log.
+    reported: [u8; MEMORY_SIZE],
+    events: Vec<SmcEvent>,
+}
+
+impl SmcDetector {
+    pub fn new() -> SmcDetector {
+        SmcDetector {
+            access: Coverage::new(),
+            reported: [0; MEMORY_SIZE],
+            events: Vec::new(),
+        }
+    }
+
+    /// Record an access to `addr` by the instruction at `pc`, reporting it if
+    /// it's self-modifying.
+    pub fn record(&mut self, pc: usize, addr: usize, flag: u8) {
+        if flag & EXECUTED != 0 && self.access.is_written(addr) {
+            self.report(addr, EXECUTED, SmcEvent::WrittenExecuted { pc, addr });
+        }
+        if flag & WRITTEN != 0 && self.access.is_executed(addr) {
+            self.report(addr, WRITTEN, SmcEvent::CodeWritten { pc, addr });
+        }
+        self.access.mark(addr, flag);
+    }
+
+    fn report(&mut self, addr: usize, kind: u8, event: SmcEvent) {
+        if self.reported[addr] & kind == 0 {
+            self.reported[addr] |= kind;
+            self.events.push(event);
+        }
+    }
+
+    /// Events reported so far that haven't been taken yet.
+    pub fn events(&self) -> &[SmcEvent] {
+        &self.events
+    }
+
+    /// Remove and return the events reported so far.
+    pub fn take_events(&mut self) -> Vec<SmcEvent> {
+        self.events.split_off(0)
+    }
+}
+
+impl Default for SmcDetector {
+    fn default() -> SmcDetector {
+        SmcDetector::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_written() {
+        let mut d = SmcDetector::new();
+
+        d.record(0x200, 0x200, EXECUTED);
+        d.record(0x202, 0x300, WRITTEN);
+        assert!(d.events().is_empty());
+
+        d.record(0x202, 0x200, WRITTEN);
+        assert_eq!(
+            d.take_events(),
+            vec![SmcEvent::CodeWritten {
+                pc: 0x202,
+                addr: 0x200
+            }]
+        );
+        assert!(d.events().is_empty());
+    }
+
+    #[test]
+    fn written_executed() {
+        let mut d = SmcDetector::new();
+
+        d.record(0x200, 0x300, WRITTEN);
+        d.record(0x300, 0x300, EXECUTED);
+
+        assert_eq!(
+            d.events(),
+            &[SmcEvent::WrittenExecuted {
+                pc: 0x300,
+                addr: 0x300
+            }]
+        );
+    }
+
+    #[test]
+    fn reported_once() {
+        let mut d = SmcDetector::new();
+
+        d.record(0x200, 0x200, EXECUTED);
+        for _ in 0..3 {
+            d.record(0x202, 0x200, WRITTEN);
+        }
+
+        assert_eq!(d.events().len(), 1);
+    }
+}
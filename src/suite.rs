@@ -0,0 +1,315 @@
+//! Conformance test suite runner.
+//!
+//! A suite is a manifest listing test ROMs, how many frames to run each one
+//! for, and which quirk profiles to run it under:
+//!
+//! ```text
+//! # rom         frames  profiles         options
+//! bcd.ch8       10      default
+//! stack.ch8     20      default,lenient
+//! keypad.ch8    10      default          key=A
+//! game.ch8      60      default          input=game.input
+//! hires.ch8     10      default          platform=hires
+//! ```
+//!
+//! `key=K` answers every `Fx0A` with key K; `input=file` replays an
+//! `InputScript`; `platform=name` runs the ROM on another `Platform`. Paths
+//! are relative to the manifest. After running, the display is compared to a
+//! golden image in `golden/<rom>.<profile>.txt` next to the manifest, in the
+//! format written by `headless::screen_text`.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use backend;
+use fault::Fault;
+use golden::{self, Mismatch};
+use headless::{self, Headless, InputScript};
+use platform::Platform;
+use quirks::Quirks;
+use CPU;
+
+/// One line of the manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Case {
+    pub rom: String,
+    pub frames: usize,
+    pub profiles: Vec<String>,
+    /// Key to answer `Fx0A` with.
+    pub key: Option<u8>,
+    /// Input script to replay, relative to the manifest.
+    pub input: Option<String>,
+    pub platform: Platform,
+}
+
+/// Parse a manifest. Blank lines and anything after a `#` are ignored.
+pub fn parse_manifest(text: &str) -> Result<Vec<Case>, String> {
+    let mut cases = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {}", number + 1, message);
+        if fields.len() < 3 {
+            return Err(error(
+                "expected a ROM, frame count and profiles".to_string(),
+            ));
+        }
+
+        let frames = fields[1]
+            .parse()
+            .map_err(|_| error(format!("bad frame count {:?}", fields[1])))?;
+        let profiles: Vec<String> = fields[2].split(',').map(String::from).collect();
+        for profile in &profiles {
+            Quirks::profile(profile).map_err(&error)?;
+        }
+
+        let mut key = None;
+        let mut input = None;
+        let mut platform = Platform::default();
+        for option in &fields[3..] {
+            if let Some(value) = option.strip_prefix("key=") {
+                key = Some(
+                    u8::from_str_radix(value, 16)
+                        .ok()
+                        .filter(|&key| key < 16)
+                        .ok_or_else(|| error(format!("bad key {:?}", value)))?,
+                );
+            } else if let Some(path) = option.strip_prefix("input=") {
+                input = Some(path.to_string());
+            } else if let Some(name) = option.strip_prefix("platform=") {
+                platform = name.parse().map_err(&error)?;
+            } else {
+                return Err(error(format!("unknown option {:?}", option)));
+            }
+        }
+
+        cases.push(Case {
+            rom: fields[0].to_string(),
+            frames,
+            profiles,
+            key,
+            input,
+            platform,
+        });
+    }
+
+    Ok(cases)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// The display didn't match the golden image.
+    Fail(Mismatch),
+    /// There's no golden image to compare with.
+    Missing,
+    /// The golden image was (re)written from this run.
+    Updated,
+}
+
+/// The result of running one ROM under one profile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaseResult {
+    pub rom: String,
+    pub profile: String,
+    pub outcome: Outcome,
+    /// The fault the ROM stopped with, if any. Some tests are expected to
+    /// fault under some profiles; the golden image decides.
+    pub fault: Option<Fault>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        match self.outcome {
+            Outcome::Pass | Outcome::Updated => true,
+            Outcome::Fail(_) | Outcome::Missing => false,
+        }
+    }
+}
+
+impl fmt::Display for CaseResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<20} {:<8} ", self.rom, self.profile)?;
+        match self.outcome {
+            Outcome::Pass => write!(f, "PASS")?,
+            Outcome::Fail(ref mismatch) => write!(f, "FAIL ({} pixels differ)", mismatch.pixels())?,
+            Outcome::Missing => write!(f, "FAIL (no golden image)")?,
+            Outcome::Updated => write!(f, "UPDATED")?,
+        }
+        if let Some(fault) = self.fault {
+            write!(f, " [{}]", fault)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Suite {
+    /// Directory the manifest is in.
+    dir: PathBuf,
+    pub cases: Vec<Case>,
+}
+
+impl Suite {
+    /// Read a suite from its manifest.
+    pub fn load(manifest: &Path) -> Result<Suite, String> {
+        let text =
+            fs::read_to_string(manifest).map_err(|e| format!("{}: {}", manifest.display(), e))?;
+        let cases = parse_manifest(&text).map_err(|e| format!("{}: {}", manifest.display(), e))?;
+        Ok(Suite {
+            dir: manifest.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            cases,
+        })
+    }
+
+    pub fn golden_path(&self, rom: &str, profile: &str) -> PathBuf {
+        self.dir
+            .join("golden")
+            .join(format!("{}.{}.txt", rom, profile))
+    }
+
+    /// Run one case under one profile and return the final display.
+    pub fn run_case(
+        &self,
+        case: &Case,
+        profile: &str,
+        backend: &str,
+    ) -> Result<(String, Option<Fault>), String> {
+        let path = self.dir.join(&case.rom);
+        let rom = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let mut cpu = CPU::new();
+        cpu.set_platform(case.platform);
+        cpu.quirks = Quirks::profile(profile)?;
+        cpu.seed_rng(0);
+        cpu.load_program(&rom);
+
+        let mut headless = Headless::new(cpu, backend::by_name(backend)?);
+        headless.key = case.key;
+        if let Some(ref input) = case.input {
+            let path = self.dir.join(input);
+            let text =
+                fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            headless.input =
+                InputScript::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        headless.run_frames(case.frames);
+        Ok((headless::screen_text(&headless.cpu), headless.cpu.fault))
+    }
+
+    /// Run every case under each of its profiles. With `update`, write the
+    /// golden images instead of comparing against them.
+    pub fn run(&self, backend: &str, update: bool) -> Result<Vec<CaseResult>, String> {
+        let mut results = Vec::new();
+
+        for case in &self.cases {
+            for profile in &case.profiles {
+                let (screen, fault) = self.run_case(case, profile, backend)?;
+                let path = self.golden_path(&case.rom, profile);
+
+                let outcome = if update {
+                    if let Some(dir) = path.parent() {
+                        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+                    }
+                    fs::write(&path, &screen).map_err(|e| format!("{}: {}", path.display(), e))?;
+                    Outcome::Updated
+                } else {
+                    match fs::read_to_string(&path) {
+                        Ok(expected) => match golden::count_differences(&screen, &expected) {
+                            0 => Outcome::Pass,
+                            _ => Outcome::Fail(Mismatch {
+                                path,
+                                expected,
+                                actual: screen,
+                            }),
+                        },
+                        Err(_) => Outcome::Missing,
+                    }
+                };
+
+                results.push(CaseResult {
+                    rom: case.rom.clone(),
+                    profile: profile.clone(),
+                    outcome,
+                    fault,
+                });
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let text = "# a comment\n\
+                    \n\
+                    bcd.ch8   10  default\n\
+                    keys.ch8  5   strict,lenient  key=a input=keys.input  # trailing\n\
+                    hires.ch8 1   default         platform=hires\n";
+
+        assert_eq!(
+            parse_manifest(text).unwrap(),
+            vec![
+                Case {
+                    rom: "bcd.ch8".to_string(),
+                    frames: 10,
+                    profiles: vec!["default".to_string()],
+                    key: None,
+                    input: None,
+                    platform: Platform::Chip8,
+                },
+                Case {
+                    rom: "keys.ch8".to_string(),
+                    frames: 5,
+                    profiles: vec!["strict".to_string(), "lenient".to_string()],
+                    key: Some(0xA),
+                    input: Some("keys.input".to_string()),
+                    platform: Platform::Chip8,
+                },
+                Case {
+                    rom: "hires.ch8".to_string(),
+                    frames: 1,
+                    profiles: vec!["default".to_string()],
+                    key: None,
+                    input: None,
+                    platform: Platform::HiRes,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_errors() {
+        assert!(parse_manifest("bcd.ch8 10")
+            .unwrap_err()
+            .starts_with("line 1: "));
+        assert!(parse_manifest("\nbcd.ch8 x default")
+            .unwrap_err()
+            .starts_with("line 2: bad frame count"));
+        assert!(parse_manifest("bcd.ch8 10 nonsense").is_err());
+        assert!(parse_manifest("bcd.ch8 10 default key=10").is_err());
+        assert!(parse_manifest("bcd.ch8 10 default color=red").is_err());
+        assert!(parse_manifest("bcd.ch8 10 default platform=vic20").is_err());
+    }
+
+    #[test]
+    fn bundled_suite() {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms/suite.txt");
+        let suite = Suite::load(&manifest).unwrap();
+
+        for name in backend::NAMES {
+            for result in suite.run(name, false).unwrap() {
+                assert!(result.passed(), "{}: {}", name, result);
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! How many instructions run in each 60 Hz frame.
+//!
+//! By default every frame runs the same number of instructions. On a real
+//! COSMAC VIP, instructions take different amounts of time: the 1802 runs
+//! at 1.76 MHz, eight clock pulses to a machine cycle, and the interpreter
+//! spends a few dozen machine cycles on something like `6xnn` and hundreds
+//! on a big sprite. The display also steals the bus for a chunk of every
+//! frame. `Timing::Vip` gives each instruction its VIP cost and runs them
+//! until a frame's worth of machine cycles is used up, so games written for
+//! the VIP run at the speed they were tuned for. Either way the delay and
+//! sound timers count down once a frame, in `CPU::vblank`.
+//!
+//! The costs are approximations of the VIP interpreter's code paths, not
+//! measurements; they get the relative speeds of instructions right, not
+//! every last cycle.
+
+use std::fmt;
+use std::str::FromStr;
+
+use headless::CYCLES_PER_FRAME;
+use {CPU, DISPLAY_WIDTH, MEMORY_SIZE};
+
+/// The VIP's 1802 clock, in Hz.
+pub const VIP_CLOCK_HZ: usize = 1_760_640;
+/// Machine cycles in a 60 Hz frame.
+pub const VIP_FRAME_CYCLES: usize = VIP_CLOCK_HZ / 8 / 60;
+/// Machine cycles a frame lost to the display: one DMA cycle for each of
+/// the 8 bytes of its 128 scanlines, plus the interrupt routine.
+pub const VIP_DISPLAY_CYCLES: usize = 128 * 8 + 46;
+/// Machine cycles a frame left over for running instructions.
+pub const VIP_CYCLES_PER_FRAME: usize = VIP_FRAME_CYCLES - VIP_DISPLAY_CYCLES;
+
+/// Machine cycles the interpreter takes to fetch and decode an instruction,
+/// before it does anything.
+const FETCH_CYCLES: usize = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timing {
+    /// The same number of instructions every frame.
+    Fixed(usize),
+    /// As many instructions as fit in a frame on the COSMAC VIP.
+    Vip,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing::Fixed(CYCLES_PER_FRAME)
+    }
+}
+
+impl FromStr for Timing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Timing, String> {
+        match s {
+            "vip" => Ok(Timing::Vip),
+            _ => s
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(Timing::Fixed)
+                .ok_or_else(|| format!("unknown timing {:?} (vip, or instructions per frame)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Timing::Fixed(n) => write!(f, "{}", n),
+            Timing::Vip => write!(f, "vip"),
+        }
+    }
+}
+
+impl Timing {
+    /// Units of time in a frame: instructions, or VIP machine cycles.
+    pub fn per_frame(&self) -> usize {
+        match *self {
+            Timing::Fixed(n) => n,
+            Timing::Vip => VIP_CYCLES_PER_FRAME,
+        }
+    }
+}
+
+/// Machine cycles the VIP interpreter takes to run the instruction at the
+/// PC, given the CPU as it is before running it.
+pub fn vip_cycles(cpu: &CPU) -> usize {
+    let opcode = (cpu.memory[cpu.pc % MEMORY_SIZE] as u16) << 8
+        | cpu.memory[(cpu.pc + 1) % MEMORY_SIZE] as u16;
+    let x = ((opcode >> 8) & 0xF) as usize;
+
+    let execute = match opcode >> 12 {
+        0x0 => match opcode {
+            // Clears the 256 bytes of display memory
+            0x00E0 => 24 + 256 * 3,
+            0x00EE => 10,
+            // Machine code: no telling how long it takes
+            _ => 10,
+        },
+        0x1 | 0x2 | 0xB => 12,
+        0x3 | 0x4 => 10,
+        0x5 | 0x9 => 14,
+        0x6 => 6,
+        0x7 => 10,
+        0x8 => 44,
+        0xA => 12,
+        0xC => 36,
+        0xD => sprite_cycles(cpu.v_reg[x], opcode & 0xF),
+        0xE => 14,
+        _ => match opcode & 0xFF {
+            0x1E => 18,
+            0x29 => 20,
+            // Converting to decimal is a loop of repeated subtraction
+            0x33 => 200,
+            // One register at a time
+            0x55 | 0x65 => 14 + 14 * (x + 1),
+            _ => 10,
+        },
+    };
+    FETCH_CYCLES + execute
+}
+
+/// Machine cycles for `Dxyn` drawing `rows` rows at column `x`. A row
+/// that isn't lined up with a byte of display memory straddles two, and is
+/// shifted into place one bit at a time.
+fn sprite_cycles(x: u8, rows: u16) -> usize {
+    let x = x as usize % DISPLAY_WIDTH;
+    let per_row = match x % 8 {
+        0 => 16,
+        shift => 24 + 2 * shift,
+    };
+    68 + rows as usize * per_row
+}
+
+/// Counts down the time left in a frame as instructions run.
+#[derive(Clone, Debug)]
+pub struct FrameClock {
+    pub timing: Timing,
+    /// Time left in this frame. Below zero if the last instruction ran
+    /// over, which is taken out of the next frame.
+    left: isize,
+    /// Cost of the instruction handed out by `batch`.
+    pending: usize,
+}
+
+impl FrameClock {
+    pub fn new(timing: Timing) -> FrameClock {
+        FrameClock {
+            timing,
+            left: 0,
+            pending: 0,
+        }
+    }
+
+    /// Add a frame's worth of time.
+    pub fn start_frame(&mut self) {
+        self.left = self.left.min(0) + self.timing.per_frame() as isize;
+    }
+
+    /// Whether the frame's time is used up.
+    pub fn frame_over(&self) -> bool {
+        self.left <= 0
+    }
+
+    /// How many instructions can run next, or 0 if the frame is over. With
+    /// VIP timing they're handed out one at a time, since the cost of each
+    /// depends on what came before.
+    pub fn batch(&mut self, cpu: &CPU) -> usize {
+        if self.frame_over() {
+            return 0;
+        }
+        match self.timing {
+            Timing::Fixed(_) => self.left as usize,
+            Timing::Vip => {
+                self.pending = vip_cycles(cpu);
+                1
+            }
+        }
+    }
+
+    /// Account for `executed` instructions of the last `batch`. Returns the
+    /// time they took.
+    pub fn ran(&mut self, executed: usize) -> usize {
+        let time = match self.timing {
+            Timing::Fixed(_) => executed,
+            Timing::Vip => self.pending * executed.min(1),
+        };
+        self.left -= time as isize;
+        time
+    }
+
+    /// Spend the rest of the frame doing nothing, as when waiting for the
+    /// display or a key. Returns the time skipped.
+    pub fn idle(&mut self) -> usize {
+        let time = self.left.max(0) as usize;
+        self.left = self.left.min(0);
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use platform::Platform;
+    use tests::cpu_with;
+
+    #[test]
+    fn sprite_costs() {
+        let mut cpu = cpu_with(Platform::Chip8, &[0xD0, 0x15, 0xD0, 0x1F]);
+        let five = vip_cycles(&cpu);
+        cpu.pc = 0x202;
+        let fifteen = vip_cycles(&cpu);
+        assert!(fifteen > five);
+
+        // Off a byte boundary costs more
+        cpu.v_reg[0] = 3;
+        assert!(vip_cycles(&cpu) > fifteen);
+        cpu.v_reg[0] = 64 + 8;
+        assert_eq!(vip_cycles(&cpu), fifteen);
+    }
+
+    #[test]
+    fn register_loads_cost_per_register() {
+        let cpu = cpu_with(Platform::Chip8, &[0xF0, 0x65]);
+        let one = vip_cycles(&cpu);
+        let cpu = cpu_with(Platform::Chip8, &[0xF3, 0x65]);
+        assert_eq!(vip_cycles(&cpu) - one, 3 * 14);
+        assert!(vip_cycles(&cpu_with(Platform::Chip8, &[0x60, 0x01])) < one);
+    }
+
+    #[test]
+    fn fixed_clock() {
+        let cpu = CPU::new();
+        let mut clock = FrameClock::new(Timing::Fixed(10));
+        clock.start_frame();
+        assert_eq!(clock.batch(&cpu), 10);
+        assert_eq!(clock.ran(4), 4);
+        assert_eq!(clock.batch(&cpu), 6);
+        assert_eq!(clock.idle(), 6);
+        assert!(clock.frame_over());
+        assert_eq!(clock.batch(&cpu), 0);
+    }
+
+    #[test]
+    fn vip_clock() {
+        // 6xnn over and over
+        let mut cpu = CPU::new();
+        for address in (0x200..0x400).step_by(2) {
+            cpu.memory[address] = 0x60;
+        }
+        let cost = vip_cycles(&cpu);
+
+        let mut clock = FrameClock::new(Timing::Vip);
+        clock.start_frame();
+        let mut executed = 0;
+        while clock.batch(&cpu) > 0 {
+            clock.ran(1);
+            cpu.pc += 2;
+            executed += 1;
+        }
+        assert_eq!(executed, VIP_CYCLES_PER_FRAME.div_ceil(cost));
+
+        // The overrun comes out of the next frame
+        clock.start_frame();
+        let overrun = executed * cost - VIP_CYCLES_PER_FRAME;
+        assert_eq!(clock.idle(), VIP_CYCLES_PER_FRAME - overrun);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!("vip".parse(), Ok(Timing::Vip));
+        assert_eq!("15".parse(), Ok(Timing::Fixed(15)));
+        assert_eq!(Timing::Vip.to_string().parse(), Ok(Timing::Vip));
+        assert!("0".parse::<Timing>().is_err());
+        assert!("fast".parse::<Timing>().is_err());
+    }
+}
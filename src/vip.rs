@@ -0,0 +1,317 @@
+//! The COSMAC VIP, for running CHIP-8 the way it first ran.
+//!
+//! Rather than interpreting CHIP-8 directly, this emulates the machine:
+//! an RCA 1802 with 4K of RAM, the CDP1861 video chip, the hex keypad and
+//! the beeper. The original CHIP-8 interpreter runs from RAM, so ROMs that
+//! call 1802 machine code with `0nnn` work, and everything takes exactly as
+//! long as it did.
+//!
+//! The interpreter and the monitor ROM are RCA's, so they aren't included
+//! and have to be supplied as images. Without a monitor, the machine starts
+//! the interpreter at 0x000 the way the monitor hands over to it.
+//!
+//! The 1861 draws 262 lines a frame, each 14 machine cycles long. Two lines
+//! before the 128 lines of the picture it interrupts the CPU, and during
+//! each of them it takes 8 bytes from R0 by DMA. `EF1` tells the CPU when
+//! the picture is about to start or end. The CHIP-8 interpreter's interrupt
+//! routine shows each row of its 64x32 display four times.
+
+use cdp1802::{Bus, Cdp1802};
+use {CPU, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+pub const RAM_SIZE: usize = 4096;
+pub const MONITOR_SIZE: usize = 512;
+/// The CHIP-8 interpreter lives below the program, from 0x000.
+pub const INTERPRETER_SIZE: usize = 512;
+
+const LINES: usize = 262;
+const CYCLES_PER_LINE: isize = 14;
+/// Line the 1861 interrupts on, two before the picture.
+const INTERRUPT_LINE: usize = 78;
+const PICTURE_START: usize = 80;
+const PICTURE_LINES: usize = 128;
+const BYTES_PER_LINE: usize = 8;
+
+/// Everything the 1802 is wired to.
+pub struct VipBus {
+    ram: Vec<u8>,
+    monitor: Vec<u8>,
+    /// After a reset the monitor shows up at 0x000 too, until the first
+    /// access with the top address bit set.
+    rom_low: bool,
+    pub keypad: [u8; 16],
+    /// Key the keypad is being asked about, set with `OUT 2`.
+    key: u8,
+    /// Turned on with `INP 1` and off with `OUT 1`.
+    display_on: bool,
+    /// Line the 1861 is on.
+    line: usize,
+}
+
+impl Bus for VipBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        if addr & 0x8000 != 0 {
+            self.rom_low = false;
+        }
+        if addr & 0x8000 != 0 || self.rom_low {
+            // Unmapped reads give 0, which is IDL
+            self.monitor
+                .get(addr as usize % MONITOR_SIZE)
+                .cloned()
+                .unwrap_or(0)
+        } else {
+            self.ram[addr as usize % RAM_SIZE]
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr & 0x8000 != 0 {
+            self.rom_low = false;
+        } else if !self.rom_low {
+            self.ram[addr as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.key = value & 0xF,
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn flag(&self, n: u8) -> bool {
+        match n {
+            // The last four lines before the picture starts and ends
+            1 => {
+                (PICTURE_START - 4..PICTURE_START).contains(&self.line)
+                    || (PICTURE_START + PICTURE_LINES - 4..PICTURE_START + PICTURE_LINES)
+                        .contains(&self.line)
+            }
+            3 => self.keypad[self.key as usize] != 0,
+            _ => false,
+        }
+    }
+}
+
+pub struct Vip {
+    pub cpu: Cdp1802,
+    pub bus: VipBus,
+    /// The picture from the last frame, 8 bytes a line.
+    picture: Vec<u8>,
+    /// Machine cycles the CPU ran past the end of the last line.
+    owed: isize,
+}
+
+impl Vip {
+    /// A VIP with 4K of RAM and the CHIP-8 interpreter loaded, booting
+    /// through `monitor` if there is one.
+    pub fn new(interpreter: &[u8], monitor: Option<&[u8]>) -> Result<Vip, String> {
+        if interpreter.len() > INTERPRETER_SIZE {
+            return Err(format!(
+                "interpreter is {} bytes, more than {}",
+                interpreter.len(),
+                INTERPRETER_SIZE
+            ));
+        }
+        if let Some(monitor) = monitor {
+            if monitor.len() > MONITOR_SIZE {
+                return Err(format!(
+                    "monitor is {} bytes, more than {}",
+                    monitor.len(),
+                    MONITOR_SIZE
+                ));
+            }
+        }
+
+        let mut ram = vec![0; RAM_SIZE];
+        ram[..interpreter.len()].copy_from_slice(interpreter);
+        let mut cpu = Cdp1802::new();
+        if monitor.is_none() {
+            // The monitor leaves the top page of RAM in R1.1, which the
+            // interpreter puts its display and variables under
+            cpu.r[1] = (RAM_SIZE - 0x100) as u16;
+        }
+
+        Ok(Vip {
+            cpu,
+            bus: VipBus {
+                ram,
+                monitor: monitor.map(<[u8]>::to_vec).unwrap_or_default(),
+                rom_low: monitor.is_some(),
+                keypad: [0; 16],
+                key: 0,
+                display_on: false,
+                line: 0,
+            },
+            picture: vec![0; PICTURE_LINES * BYTES_PER_LINE],
+            owed: 0,
+        })
+    }
+
+    /// Load a CHIP-8 program at 0x200.
+    pub fn load_program(&mut self, program: &[u8]) {
+        let ram = &mut self.bus.ram[0x200..];
+        let len = program.len().min(ram.len());
+        ram[..len].copy_from_slice(&program[..len]);
+    }
+
+    /// Run one 60 Hz frame of 262 lines.
+    pub fn run_frame(&mut self) {
+        let mut interrupt = false;
+        for line in 0..LINES {
+            self.bus.line = line;
+            let mut left = CYCLES_PER_LINE - self.owed;
+
+            if line == INTERRUPT_LINE && self.bus.display_on {
+                interrupt = true;
+            }
+            if line == PICTURE_START {
+                interrupt = false;
+            }
+            let picture_line = line.wrapping_sub(PICTURE_START);
+            if picture_line < PICTURE_LINES {
+                for byte in 0..BYTES_PER_LINE {
+                    self.picture[picture_line * BYTES_PER_LINE + byte] = if self.bus.display_on {
+                        left -= 1;
+                        self.cpu.dma_out(&mut self.bus)
+                    } else {
+                        0
+                    };
+                }
+            }
+
+            while left > 0 {
+                if interrupt && self.cpu.ie {
+                    interrupt = false;
+                    left -= self.cpu.interrupt() as isize;
+                } else {
+                    left -= self.cpu.step(&mut self.bus) as isize;
+                }
+            }
+            self.owed = -left;
+        }
+    }
+
+    /// Whether the beeper is sounding.
+    pub fn beeping(&self) -> bool {
+        self.cpu.q
+    }
+
+    /// Show the last frame on `cpu`'s display, one pixel for each 4 lines
+    /// of the picture as the CHIP-8 interpreter draws it, and the beeper
+    /// through its sound timer.
+    pub fn show(&self, cpu: &mut CPU) {
+        let lines_per_row = PICTURE_LINES / DISPLAY_HEIGHT;
+        for y in 0..DISPLAY_HEIGHT {
+            let line = &self.picture[y * lines_per_row * BYTES_PER_LINE..][..BYTES_PER_LINE];
+            for x in 0..DISPLAY_WIDTH {
+                let on = line[x / 8] & (0x80 >> (x % 8)) != 0;
+                cpu.set_pixel(y * DISPLAY_WIDTH + x, on as u8);
+            }
+        }
+        cpu.sound_timer = self.beeping() as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timing::VIP_FRAME_CYCLES;
+
+    #[test]
+    fn frame_length() {
+        assert_eq!(LINES * CYCLES_PER_LINE as usize, VIP_FRAME_CYCLES);
+    }
+
+    #[test]
+    fn starts_interpreter() {
+        // SEQ, then BR to itself
+        let mut vip = Vip::new(&[0x7B, 0x30, 0x01], None).unwrap();
+        assert_eq!(vip.cpu.r[1], 0x0F00);
+        vip.run_frame();
+        assert!(vip.beeping());
+
+        let mut cpu = CPU::new();
+        vip.show(&mut cpu);
+        assert_eq!(cpu.sound_timer, 1);
+    }
+
+    #[test]
+    fn monitor_at_reset() {
+        // LBR 8003, SEQ, BR to itself
+        let monitor = [0xC0, 0x80, 0x03, 0x7B, 0x30, 0x04];
+        let mut vip = Vip::new(&[0xAB], Some(&monitor)).unwrap();
+        assert_eq!(vip.bus.read(0x000), 0xC0);
+        vip.run_frame();
+        assert!(vip.beeping());
+        assert_eq!(vip.bus.read(0x000), 0xAB);
+
+        assert!(Vip::new(&[0; 513], None).is_err());
+        assert!(Vip::new(&[], Some(&[0; 513])).is_err());
+    }
+
+    #[test]
+    fn keypad() {
+        // SEX 0, OUT 2 asking for key 5, then B3 to SEQ if it's down
+        let program = [0xE0, 0x62, 0x05, 0x36, 0x07, 0x30, 0x05, 0x7B, 0x30, 0x08];
+        let mut vip = Vip::new(&program, None).unwrap();
+        vip.run_frame();
+        assert!(!vip.beeping());
+
+        let mut vip = Vip::new(&program, None).unwrap();
+        vip.bus.keypad[5] = 1;
+        vip.run_frame();
+        assert!(vip.beeping());
+    }
+
+    #[test]
+    fn picture() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let mut program = vec![
+            // DMA moves R0 on, so run from R3 like the interpreter does
+            0xF8, 0x00, 0xB3, 0xF8, 0x08, 0xA3, // R3 = 0008
+            0xD3, 0x00,                         // SEP 3
+            0xF8, 0x00, 0xB1, 0xF8, 0x40, 0xA1, // R1 = 0040
+            0xF8, 0x0E, 0xB2, 0xF8, 0xFF, 0xA2, // R2 = 0EFF
+            0xE2, 0x69,                         // SEX 2, INP 1
+            0x30, 0x16,                         // BR to itself
+        ];
+        program.resize(0x3F, 0);
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        program.extend_from_slice(&[
+            0x70,                               // 3F: RET
+            0x22, 0x78,                         // 40: DEC 2, SAV
+            0xF8, 0x03, 0xB0, 0xF8, 0x00, 0xA0, // R0 = 0300
+            0x30, 0x3F,                         // BR 3F
+        ]);
+        let mut vip = Vip::new(&program, None).unwrap();
+        // Row 2 is lines 8 to 11 of the picture
+        vip.bus.ram[0x300 + 8 * BYTES_PER_LINE] = 0x80;
+        vip.bus.ram[0x300 + 8 * BYTES_PER_LINE + 7] = 0x01;
+        vip.run_frame();
+
+        let mut cpu = CPU::new();
+        vip.show(&mut cpu);
+        let lit: Vec<usize> = (0..DISPLAY_WIDTH * DISPLAY_HEIGHT)
+            .filter(|&pixel| cpu.get_pixel(pixel) == 1)
+            .collect();
+        assert_eq!(lit, vec![2 * DISPLAY_WIDTH, 3 * DISPLAY_WIDTH - 1]);
+        // Interrupts were handled and turned back on
+        assert!(vip.cpu.ie);
+        assert_eq!(vip.cpu.r[1], 0x0040);
+
+        // OUT 1 turns the display off, and the picture goes blank
+        vip.bus.output(1, 0);
+        vip.run_frame();
+        vip.show(&mut cpu);
+        assert_eq!(cpu.get_pixel(2 * DISPLAY_WIDTH), 0);
+    }
+}